@@ -1,71 +1,308 @@
 use std::{
-    collections::{BTreeMap, BTreeSet},
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, RwLock,
-    },
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    env,
+    io::{Read as _, Write as _},
+    sync::{Arc, Mutex, RwLock},
 };
 
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use gossip_glomers::{
-    error::MaelstromError, node_id, parse_node_id, Handler, MaelstromMessage, Node,
+    error::MaelstromError, maelstrom_payload, node_id, parse_node_id, Handler, MaelstromMessage,
+    Node, NodeId,
 };
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serializer};
+use serde_json::Value;
 use tokio::time::Duration;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum RequestPayload {
-    // Client requests
-    Broadcast {
-        message: u64,
-    },
-    Read,
-    Topology {
-        topology: BTreeMap<String, Vec<String>>,
-    },
-    Gossip {
-        messages: BTreeSet<u64>,
-    },
+// Overridden with GOSSIP_INTERVAL_MS, a shorter interval lowers latency at the
+// cost of sending more messages
+const DEFAULT_GOSSIP_INTERVAL: Duration = Duration::from_millis(100);
+// Unacknowledged gossip is simply sent again on a later tick
+const GOSSIP_TIMEOUT: Duration = Duration::from_millis(500);
+// Upper bound on the number of messages in a single gossip message, larger
+// diffs are split over several gossip messages which are acknowledged
+// separately
+const MAX_GOSSIP_BATCH: usize = 1000;
+
+// Every message a node has seen, and what it knows each neighbor has seen.
+// Message ids are dense, so a roaring bitmap takes a fraction of the memory
+// of a BTreeSet and computes the gossip diff much faster. Build without the
+// roaring feature to use a BTreeSet instead.
+#[cfg(feature = "roaring")]
+type MessageSet = roaring::RoaringTreemap;
+#[cfg(not(feature = "roaring"))]
+type MessageSet = BTreeSet<u64>;
+
+maelstrom_payload! {
+    message enum RequestPayload {
+        // Client requests
+        Broadcast {
+            message: u64,
+        },
+        Read,
+        Topology {
+            topology: BTreeMap<String, Vec<String>>,
+        },
+        Gossip {
+            messages: BTreeSet<u64>,
+        },
+        // Same as Gossip, with the messages encoded by encode_messages. Only
+        // sent for batches of at least GOSSIP_COMPRESS_THRESHOLD messages.
+        GossipCompressed {
+            data: String,
+        },
+    }
+}
+
+maelstrom_payload! {
+    message enum ResponsePayload<'a> {
+        // Client responses
+        ReadOk {
+            #[serde(serialize_with = "serialize_messages")]
+            #[serde(deserialize_with = "deserialize_messages")]
+            messages: Cow<'a, MessageSet>,
+        },
+        // Acknowledges a gossip message by echoing the messages it carried, which
+        // the neighbor has now stored. Only the ids in the gossip message itself,
+        // so the acknowledgement is never larger than a gossip batch.
+        GossipOk { messages: Cow<'a, BTreeSet<u64>> },
+    }
+}
+
+// Read responses list the messages, whichever set they are stored in
+fn serialize_messages<S>(messages: &MessageSet, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_seq(messages.iter())
+}
+
+fn deserialize_messages<'de, 'a, D>(deserializer: D) -> Result<Cow<'a, MessageSet>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let messages = Vec::<u64>::deserialize(deserializer)?;
+    Ok(Cow::Owned(messages.into_iter().collect()))
 }
 
-#[allow(clippy::enum_variant_names)]
-#[derive(Serialize, Clone, Debug)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum ResponsePayload<'a> {
-    // Client responses
-    BroadcastOk,
-    ReadOk { messages: &'a BTreeSet<u64> },
-    TopologyOk,
+// Messages as the differences between consecutive ids, each written as a
+// LEB128 varint, then gzipped and base64 encoded to fit in a JSON string.
+// Message ids tend to be small and dense, so most deltas take a single byte.
+fn encode_messages(messages: &BTreeSet<u64>) -> String {
+    let mut varints = Vec::new();
+    let mut previous = 0;
+    for &message in messages {
+        let mut delta = message - previous;
+        previous = message;
+        while delta >= 0x80 {
+            #[allow(clippy::cast_possible_truncation)]
+            varints.push((delta as u8) | 0x80);
+            delta >>= 7;
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        varints.push(delta as u8);
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    // Writing to a Vec can't fail
+    encoder.write_all(&varints).unwrap();
+    STANDARD.encode(encoder.finish().unwrap())
+}
+
+fn decode_messages(data: &str) -> Result<BTreeSet<u64>, MaelstromError> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        MaelstromError::malformed_request(format!("Invalid compressed gossip: {e}"))
+    };
+    let compressed = STANDARD.decode(data).map_err(|e| invalid(&e))?;
+    let mut varints = Vec::new();
+    GzDecoder::new(compressed.as_slice())
+        .read_to_end(&mut varints)
+        .map_err(|e| invalid(&e))?;
+
+    let mut messages = BTreeSet::new();
+    let mut previous: u64 = 0;
+    let mut delta: u64 = 0;
+    let mut shift = 0;
+    for byte in varints {
+        if shift >= u64::BITS {
+            return Err(invalid(&"varint overflows u64"));
+        }
+        delta |= u64::from(byte & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            previous = previous
+                .checked_add(delta)
+                .ok_or_else(|| invalid(&"message id overflows u64"))?;
+            messages.insert(previous);
+            delta = 0;
+            shift = 0;
+        }
+    }
+    if shift != 0 {
+        return Err(invalid(&"truncated varint"));
+    }
+    Ok(messages)
 }
 
-#[derive(Clone)]
 struct BroadcastHandler {
     node: Node,
-    seen_messages: Arc<RwLock<BTreeSet<u64>>>,
-    neighbors_seen: Arc<RwLock<BTreeMap<u32, BTreeSet<u64>>>>,
+    seen_messages: RwLock<MessageSet>,
+    neighbors_seen: RwLock<BTreeMap<u32, MessageSet>>,
+    // Number of gossip batches sent to each neighbor that haven't been
+    // acknowledged or timed out yet. Neighbors with any are skipped until
+    // they have all been answered, so a slow or partitioned neighbor gets one
+    // round at a time instead of a pile of them, and the messages that arrive
+    // in the meantime go out together in the round after.
+    in_flight: Mutex<BTreeMap<u32, usize>>,
+    // Batches with at least this many messages are sent as GossipCompressed,
+    // None to never compress
+    compress_threshold: Option<usize>,
 }
 
 impl BroadcastHandler {
-    async fn gossip(&self) {
-        // For each of our direct neighbors
-        // (excluding the one which we received the gossip message from...)
-        for (&neighbor, messages) in self.neighbors_seen.read().unwrap().iter() {
-            // Spawn a new task to send gossip message,
-            //since it may take a long time to receive a response
-            let node = self.node.clone();
-
-            let messages = self
-                .seen_messages
+    // Takes the Arc shared with Node::run, so the gossip tasks can hold on to
+    // the handler without copying any of its state
+    fn gossip(self: &Arc<Self>) {
+        // For each of our direct neighbors, collect the messages they haven't
+        // acknowledged yet
+        let gossips: Vec<(u32, Vec<u64>)> = {
+            let seen_messages = self.seen_messages.read().unwrap();
+            let in_flight = self.in_flight.lock().unwrap();
+            self.neighbors_seen
                 .read()
                 .unwrap()
-                .difference(messages)
-                .copied()
-                .collect();
+                .iter()
+                .filter(|(neighbor, _)| !in_flight.contains_key(neighbor))
+                .map(|(&neighbor, messages)| {
+                    (
+                        neighbor,
+                        (&*seen_messages - messages).into_iter().collect::<Vec<_>>(),
+                    )
+                })
+                .filter(|(_, messages)| !messages.is_empty())
+                .collect()
+        };
+
+        for (neighbor, messages) in gossips {
+            let batches = messages.chunks(MAX_GOSSIP_BATCH);
+            self.in_flight
+                .lock()
+                .unwrap()
+                .insert(neighbor, batches.len());
+            for batch in batches {
+                // Spawn a new task to send gossip message,
+                // since it may take a long time to receive a response
+                let handler = Arc::clone(self);
+                let messages = batch.iter().copied().collect();
+                let gossip = match self.compress_threshold {
+                    Some(threshold) if batch.len() >= threshold => {
+                        RequestPayload::GossipCompressed {
+                            data: encode_messages(&messages),
+                        }
+                    }
+                    _ => RequestPayload::Gossip { messages },
+                };
+                tokio::spawn(async move {
+                    // Send message and wait for response
+                    let res = handler
+                        .node
+                        .send_rpc_to(neighbor, gossip, Some(GOSSIP_TIMEOUT))
+                        .await;
+                    match res {
+                        // Only stop sending messages once the neighbor confirmed it has them
+                        Ok(ResponsePayload::GossipOk { messages }) => {
+                            if let Some(known) =
+                                handler.neighbors_seen.write().unwrap().get_mut(&neighbor)
+                            {
+                                known.extend(messages.iter());
+                            }
+                        }
+                        Ok(response) => {
+                            eprintln!(
+                                "ERROR: Unexpected gossip response from {neighbor}: {response:?}"
+                            );
+                        }
+                        // Retried on the next tick
+                        Err(_) => {}
+                    }
+                    handler.batch_done(neighbor);
+                });
+            }
+        }
+    }
+}
+
+impl BroadcastHandler {
+    fn batch_done(&self, neighbor: u32) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(outstanding) = in_flight.get_mut(&neighbor) {
+            *outstanding -= 1;
+            if *outstanding == 0 {
+                in_flight.remove(&neighbor);
+            }
+        }
+    }
+
+    fn receive_gossip(
+        &self,
+        gossip_msg: &MaelstromMessage<RequestPayload>,
+        messages: Cow<'_, BTreeSet<u64>>,
+    ) -> Result<(), MaelstromError> {
+        // Received propagation message, store it in local set
+        self.seen_messages.write().unwrap().extend(messages.iter());
+        // The sender obviously has these, no need to send them back. Only
+        // peers are tracked, a client sending gossip just gets its reply.
+        if let Ok(NodeId::Node(peer)) = gossip_msg.src.parse() {
+            if let Some(known) = self.neighbors_seen.write().unwrap().get_mut(&peer) {
+                known.extend(messages.iter());
+            }
+        }
+        self.node
+            .reply(gossip_msg, ResponsePayload::GossipOk { messages })?;
+        Ok(())
+    }
+}
 
-            // Send message and wait for response
-            node.send(&node_id(neighbor), RequestPayload::Gossip { messages });
+// Parent and children of node in a BFS spanning tree of the topology rooted at
+// root, or None if the tree doesn't reach every node
+fn spanning_tree_neighbors(
+    topology: &BTreeMap<String, Vec<String>>,
+    root: &str,
+    node: &str,
+) -> Option<Vec<String>> {
+    // Mapping from each reached node to its parent in the tree
+    let mut parents: BTreeMap<&str, Option<&str>> = BTreeMap::new();
+    let mut queue = VecDeque::new();
+    if topology.contains_key(root) {
+        parents.insert(root, None);
+        queue.push_back(root);
+    }
+    while let Some(current) = queue.pop_front() {
+        for neighbor in topology.get(current).into_iter().flatten() {
+            if !parents.contains_key(neighbor.as_str()) {
+                parents.insert(neighbor, Some(current));
+                queue.push_back(neighbor);
+            }
         }
     }
+
+    if parents.len() != topology.len() {
+        return None;
+    }
+    let parent = parents.get(node).copied().flatten();
+    let children = parents
+        .iter()
+        .filter(|(_, &parent)| parent == Some(node))
+        .map(|(&child, _)| child);
+    Some(
+        parent
+            .into_iter()
+            .chain(children)
+            .map(String::from)
+            .collect(),
+    )
 }
 
 impl Handler<RequestPayload> for BroadcastHandler {
@@ -80,71 +317,243 @@ impl Handler<RequestPayload> for BroadcastHandler {
                 // Store message in local set
                 self.seen_messages.write().unwrap().insert(*message);
                 // Confirm that we received and stored message
-                self.node.reply(broadcast_msg, ResponsePayload::BroadcastOk);
+                self.node.ack(broadcast_msg, "broadcast_ok")?;
             }
             RequestPayload::Gossip { messages } => {
-                // Received propagation message, store it in local set
-                self.seen_messages.write().unwrap().extend(messages);
-                self.neighbors_seen
-                    .write()
-                    .unwrap()
-                    .get_mut(&parse_node_id(&broadcast_msg.src).unwrap())
-                    .unwrap()
-                    .extend(messages)
+                self.receive_gossip(broadcast_msg, Cow::Borrowed(messages))?;
+            }
+            RequestPayload::GossipCompressed { data } => {
+                self.receive_gossip(broadcast_msg, Cow::Owned(decode_messages(data)?))?;
             }
             RequestPayload::Read => {
                 // Respond with list of received messages
                 self.node.reply(
                     broadcast_msg,
                     ResponsePayload::ReadOk {
-                        messages: &self.seen_messages.read().unwrap(),
+                        messages: Cow::Borrowed(&self.seen_messages.read().unwrap()),
                     },
-                );
+                )?;
             }
             RequestPayload::Topology { topology } => {
-                // Initialization of node topology, store list of direct neighbors locally.
+                // Initialization of node topology, store list of neighbors to gossip
+                // with locally. Prefer the edges of a spanning tree so each message
+                // only crosses every node once, otherwise use all direct neighbors.
+                let me = self.node.id_str();
+                let direct_neighbors = topology
+                    .get(me)
+                    .ok_or_else(|| MaelstromError::node_not_found("Invalid node in topology"))?;
+                let neighbors = spanning_tree_neighbors(topology, &node_id(0), me)
+                    .unwrap_or_else(|| direct_neighbors.clone());
+                let neighbors = neighbors
+                    .iter()
+                    .map(|n| match n.parse() {
+                        Ok(NodeId::Node(id)) => Ok(id),
+                        _ => Err(MaelstromError::malformed_request(format!(
+                            "Invalid node {n} in topology"
+                        ))),
+                    })
+                    .collect::<Result<BTreeSet<_>, _>>()?;
+                // A later topology only changes who we gossip with, what
+                // remaining neighbors are known to have seen stays valid
                 {
                     let mut guard = self.neighbors_seen.write().unwrap();
-                    for neighbor in topology
-                        .get(&node_id(self.node.id))
-                        .ok_or_else(|| MaelstromError::node_not_found("Invalid node in topology"))?
-                        .iter()
-                        .map(|n| parse_node_id(n))
-                    {
-                        guard.insert(neighbor?, BTreeSet::new());
+                    guard.retain(|neighbor, _| neighbors.contains(neighbor));
+                    for neighbor in neighbors {
+                        guard.entry(neighbor).or_default();
                     }
                 }
 
-                self.node.reply(broadcast_msg, ResponsePayload::TopologyOk);
+                self.node.ack(broadcast_msg, "topology_ok")?;
             }
         }
 
         Ok(())
     }
+
+    // Gossip with every peer until the topology arrives, so messages
+    // broadcast before it still spread
+    async fn on_init(&self, node: &Node) {
+        let mut guard = self.neighbors_seen.write().unwrap();
+        for peer in node.peers() {
+            if let Ok(peer) = parse_node_id(&peer) {
+                guard.entry(peer).or_default();
+            }
+        }
+    }
+
+    async fn handle_debug(&self, mut node_state: Value) -> Value {
+        node_state["seen_messages"] = self.seen_messages.read().unwrap().len().into();
+        node_state["neighbors"] = self
+            .neighbors_seen
+            .read()
+            .unwrap()
+            .keys()
+            .map(|&neighbor| node_id(neighbor))
+            .collect();
+        node_state["gossip_in_flight"] = self.in_flight.lock().unwrap().len().into();
+        node_state
+    }
 }
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
-    let node = Node::init()?;
-    let handler = BroadcastHandler {
-        node: node.clone(),
-        seen_messages: Arc::new(RwLock::new(BTreeSet::new())),
-        neighbors_seen: Arc::new(RwLock::new(BTreeMap::new())),
+    let gossip_interval = match env::var("GOSSIP_INTERVAL_MS") {
+        Ok(interval) => Duration::from_millis(interval.parse()?),
+        Err(_) => DEFAULT_GOSSIP_INTERVAL,
     };
-    let closed = Arc::new(AtomicBool::new(false));
-
-    let handler_clone = handler.clone();
-    let closed_clone = closed.clone();
-    let handle = tokio::spawn(async move {
-        while !closed_clone.load(Ordering::Relaxed) {
-            tokio::time::sleep(Duration::from_millis(100)).await;
-            handler_clone.gossip().await;
-        }
+    // Compression is off unless GOSSIP_COMPRESS_THRESHOLD is set. Nodes always
+    // accept both kinds of gossip, so it can differ between nodes.
+    let compress_threshold = match env::var("GOSSIP_COMPRESS_THRESHOLD") {
+        Ok(threshold) => Some(threshold.parse()?),
+        Err(_) => None,
+    };
+
+    let node = Node::init().await?;
+    let handler = Arc::new(BroadcastHandler {
+        node: node.clone(),
+        seen_messages: RwLock::new(MessageSet::new()),
+        neighbors_seen: RwLock::new(BTreeMap::new()),
+        in_flight: Mutex::new(BTreeMap::new()),
+        compress_threshold,
     });
 
-    let run_result = node.run(handler).await;
-    closed.store(true, Ordering::Relaxed);
+    // Gossip until the node shuts down
+    let gossip_handler = Arc::clone(&handler);
+    node.spawn_periodic(gossip_interval, move |_| gossip_handler.gossip());
 
-    handle.await.unwrap();
-    Ok(run_result?)
+    Ok(node.run(handler).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers::node::testing::TestHarness;
+    use serde_json::json;
+
+    use super::*;
+
+    fn message(src: &str, body: &Value) -> String {
+        json!({"src": src, "dest": "n1", "body": body}).to_string()
+    }
+
+    fn topology(msg_id: u64, edges: &[(u32, u32)]) -> String {
+        let mut topology: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for &(a, b) in edges {
+            topology.entry(node_id(a)).or_default().push(node_id(b));
+            topology.entry(node_id(b)).or_default().push(node_id(a));
+        }
+        message(
+            "c1",
+            &json!({"type": "topology", "msg_id": msg_id, "topology": topology}),
+        )
+    }
+
+    #[tokio::test]
+    async fn topology_change_keeps_tracking_for_remaining_neighbors() {
+        let handler = Arc::new(Mutex::new(None));
+        let output = TestHarness::new("n1", (0..4).map(node_id).collect())
+            // Spanning tree n0 - n1 - n2 - n3, so n1 gossips with n0 and n2
+            .input(topology(1, &[(0, 1), (1, 2), (2, 3)]))
+            .input(message(
+                "n2",
+                &json!({"type": "gossip", "msg_id": 2, "messages": [1, 2]}),
+            ))
+            // Spanning tree n0 - n2 - n1 - n3, so n1 gossips with n2 and n3
+            .input(topology(3, &[(0, 2), (2, 1), (1, 3)]))
+            .run(|node| {
+                let broadcast = Arc::new(BroadcastHandler {
+                    node,
+                    seen_messages: RwLock::new(MessageSet::new()),
+                    neighbors_seen: RwLock::new(BTreeMap::new()),
+                    in_flight: Mutex::new(BTreeMap::new()),
+                    compress_threshold: None,
+                });
+                *handler.lock().unwrap() = Some(Arc::clone(&broadcast));
+                broadcast
+            })
+            .await
+            .unwrap();
+        assert_eq!(output.len(), 4, "{output:#?}");
+
+        let handler = handler.lock().unwrap().take().unwrap();
+        let neighbors_seen = handler.neighbors_seen.read().unwrap();
+        assert_eq!(
+            neighbors_seen.keys().copied().collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        // n2 already has what it gossiped to us, n3 is new
+        assert_eq!(neighbors_seen[&2], MessageSet::from_iter([1, 2]));
+        assert_eq!(neighbors_seen[&3], MessageSet::new());
+    }
+
+    #[tokio::test]
+    async fn gossip_and_topology_from_clients_dont_crash() {
+        let output = TestHarness::new("n1", (0..2).map(node_id).collect())
+            .input(message(
+                "c2",
+                &json!({"type": "gossip", "msg_id": 1, "messages": [1]}),
+            ))
+            .input(message(
+                "c2",
+                &json!({"type": "topology", "msg_id": 2, "topology": {"n1": ["c3"]}}),
+            ))
+            .input(message("c2", &json!({"type": "read", "msg_id": 3})))
+            .run(|node| {
+                Arc::new(BroadcastHandler {
+                    node,
+                    seen_messages: RwLock::new(MessageSet::new()),
+                    neighbors_seen: RwLock::new(BTreeMap::new()),
+                    in_flight: Mutex::new(BTreeMap::new()),
+                    compress_threshold: None,
+                })
+            })
+            .await
+            .unwrap();
+        let output: Vec<Value> = output
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(output.len(), 4, "{output:#?}");
+        assert_eq!(output[1]["body"]["type"], "gossip_ok");
+        assert_eq!(output[2]["body"]["type"], "error");
+        assert_eq!(output[2]["body"]["code"], 12);
+        assert_eq!(output[3]["body"]["messages"], json!([1]));
+    }
+
+    #[test]
+    fn compressed_gossip_round_trips() {
+        let cases = [
+            BTreeSet::new(),
+            BTreeSet::from([0]),
+            BTreeSet::from([3, 127, 128, 129, 20_000]),
+            (0..5000).collect(),
+            BTreeSet::from([1, u64::from(u32::MAX), u64::MAX]),
+        ];
+        for messages in cases {
+            let data = encode_messages(&messages);
+            assert_eq!(decode_messages(&data).unwrap(), messages);
+        }
+    }
+
+    #[test]
+    fn compressed_gossip_is_smaller() {
+        let messages: BTreeSet<u64> = (0..5000).collect();
+        let plain = serde_json::to_string(&messages).unwrap();
+        assert!(encode_messages(&messages).len() * 10 < plain.len());
+    }
+
+    #[test]
+    fn invalid_compressed_gossip_is_rejected() {
+        let gzip = |bytes: &[u8]| {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(bytes).unwrap();
+            STANDARD.encode(encoder.finish().unwrap())
+        };
+        assert!(decode_messages("not base64!").is_err());
+        assert!(decode_messages(&STANDARD.encode(b"not gzip")).is_err());
+        // Continuation bit set on the last byte
+        assert!(decode_messages(&gzip(&[0x05, 0x80])).is_err());
+        // Deltas adding up to more than u64::MAX
+        let max = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        assert!(decode_messages(&gzip(&[&max[..], &[0x01]].concat())).is_err());
+    }
 }
@@ -1,31 +1,24 @@
 use gossip_glomers::{
-    error::{
-        error_type::{self},
-        GlomerError, MaelstromError,
-    },
-    seq_kv_client::SeqKvClient,
-    Handler, MaelstromMessage, Node,
+    error::MaelstromError, kv_client::KvClient, maelstrom_payload, Handler, MaelstromMessage, Node,
 };
-use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Deserialize, Clone, Debug)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum RequestPayload {
-    Add { delta: i64 },
-    Read,
+maelstrom_payload! {
+    request enum RequestPayload {
+        Add { delta: i64 },
+        Read,
+    }
 }
 
-#[derive(Serialize, Clone, Debug)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum ResponsePayload {
-    AddOk,
-    ReadOk { value: i64 },
+maelstrom_payload! {
+    response enum ResponsePayload {
+        ReadOk { value: i64 },
+    }
 }
 
 struct CounterHandler {
     node: Node,
-    client: SeqKvClient,
+    client: KvClient,
 }
 
 impl Handler<RequestPayload> for CounterHandler {
@@ -35,52 +28,18 @@ impl Handler<RequestPayload> for CounterHandler {
     ) -> Result<(), MaelstromError> {
         match counter_msg.body.payload {
             RequestPayload::Add { delta } => {
-                loop {
-                    let current_value = self.client.read_int("counter").await.unwrap_or(0);
-                    let new_value = current_value + delta;
-                    // TODO we only care about eventual consistency, do we need to wait for response
-                    let res = self
-                        .client
-                        .compare_and_swap(
-                            "counter",
-                            &current_value.to_string(),
-                            &new_value.to_string(),
-                            true,
-                        )
-                        .await;
-                    match res {
-                        Err(GlomerError::Maelstrom(MaelstromError {
-                            code: error_type::PRECONDITION_FAILED,
-                            ..
-                        })) => {
-                            continue;
-                        }
-                        Ok(())
-                        | Err(GlomerError::Maelstrom(MaelstromError {
-                            code: error_type::KEY_DOES_NOT_EXIST,
-                            ..
-                        })) => {
-                            break;
-                        }
-                        Err(e) => return Err(e.into()),
-                    }
-                }
-                self.node.reply(counter_msg, ResponsePayload::AddOk);
+                self.client
+                    .update("counter", 0, |value| value + delta)
+                    .await?;
+                self.node.ack(counter_msg, "add_ok")?;
             }
             RequestPayload::Read => {
                 let read_marker = Uuid::now_v7().to_string();
                 self.client.write("read_marker", &read_marker).await?;
 
-                let value = match self.client.read_int("counter").await {
-                    Ok(v) => v,
-                    Err(GlomerError::Maelstrom(MaelstromError {
-                        code: error_type::KEY_DOES_NOT_EXIST,
-                        ..
-                    })) => 0,
-                    Err(e) => return Err(e.into()),
-                };
+                let value = self.client.read_int_or("counter", 0).await?;
                 self.node
-                    .reply(counter_msg, ResponsePayload::ReadOk { value });
+                    .reply(counter_msg, ResponsePayload::ReadOk { value })?;
             }
         }
 
@@ -90,11 +49,11 @@ impl Handler<RequestPayload> for CounterHandler {
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
-    let node = Node::init()?;
+    let node = Node::init().await?;
     // TODO reduce node cloning?
     let handler = CounterHandler {
         node: node.clone(),
-        client: SeqKvClient::new(node.clone()),
+        client: KvClient::seq(node.clone()),
     };
     Ok(node.run(handler).await?)
 }
@@ -0,0 +1,156 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use gossip_glomers::{error::MaelstromError, maelstrom_payload, Handler, MaelstromMessage, Node};
+use serde::{Deserialize, Serialize};
+use tokio::time::Duration;
+
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(100);
+
+// Last write wins, ordered by Lamport timestamp and then by the writing node,
+// so every node picks the same winner no matter in which order it hears of
+// writes
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Timestamp {
+    clock: u64,
+    node: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+struct Register {
+    timestamp: Timestamp,
+    value: i64,
+}
+
+maelstrom_payload! {
+    message enum RequestPayload {
+        // Client requests, from Maelstrom's lin-kv workload
+        Read { key: u64 },
+        Write { key: u64, value: i64 },
+        // Every register known to the sender. A list rather than a map, since
+        // integer map keys don't survive deserialization through a tagged enum.
+        Gossip { registers: Vec<(u64, Register)> },
+    }
+}
+
+maelstrom_payload! {
+    response enum ResponsePayload {
+        ReadOk { value: i64 },
+    }
+}
+
+// Totally available register store - reads and writes only ever touch local
+// state, which is gossiped to every other node in the background. Nodes keep
+// serving requests through a partition and converge once it heals, but reads
+// may be stale in the meantime, so this is not linearizable.
+struct RegisterHandler {
+    node: Node,
+    // Lamport clock, advanced past every timestamp we hear of
+    clock: AtomicU64,
+    registers: RwLock<BTreeMap<u64, Register>>,
+}
+
+impl RegisterHandler {
+    fn gossip(&self) {
+        let registers: Vec<_> = self
+            .registers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(&key, &register)| (key, register))
+            .collect();
+        if registers.is_empty() {
+            return;
+        }
+        // Lost gossip is made up for by the next round
+        if let Err(e) = self
+            .node
+            .broadcast_to_peers(RequestPayload::Gossip { registers })
+        {
+            eprintln!("ERROR: Failed to gossip: {e}");
+        }
+    }
+}
+
+impl Handler<RequestPayload> for RegisterHandler {
+    async fn handle(
+        &self,
+        register_msg: &MaelstromMessage<RequestPayload>,
+    ) -> Result<(), MaelstromError> {
+        match &register_msg.body.payload {
+            RequestPayload::Read { key } => {
+                let register = self.registers.read().unwrap().get(key).copied();
+                let Some(register) = register else {
+                    return Err(MaelstromError::key_does_not_exist(format!(
+                        "Key {key} does not exist"
+                    )));
+                };
+                self.node.reply(
+                    register_msg,
+                    ResponsePayload::ReadOk {
+                        value: register.value,
+                    },
+                )?;
+            }
+            RequestPayload::Write { key, value } => {
+                let timestamp = Timestamp {
+                    clock: self.clock.fetch_add(1, Ordering::SeqCst) + 1,
+                    node: self.node.id,
+                };
+                let register = Register {
+                    timestamp,
+                    value: *value,
+                };
+                self.registers
+                    .write()
+                    .unwrap()
+                    .entry(*key)
+                    .and_modify(|current| {
+                        if current.timestamp < timestamp {
+                            *current = register;
+                        }
+                    })
+                    .or_insert(register);
+                self.node.ack(register_msg, "write_ok")?;
+            }
+            RequestPayload::Gossip { registers } => {
+                let mut guard = self.registers.write().unwrap();
+                for &(key, register) in registers {
+                    self.clock
+                        .fetch_max(register.timestamp.clock, Ordering::SeqCst);
+                    guard
+                        .entry(key)
+                        .and_modify(|current| {
+                            if current.timestamp < register.timestamp {
+                                *current = register;
+                            }
+                        })
+                        .or_insert(register);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    let node = Node::init().await?;
+    let handler = Arc::new(RegisterHandler {
+        node: node.clone(),
+        clock: AtomicU64::new(0),
+        registers: RwLock::new(BTreeMap::new()),
+    });
+
+    // Gossip until the node shuts down
+    let gossip_handler = Arc::clone(&handler);
+    node.spawn_periodic(GOSSIP_INTERVAL, move |_| gossip_handler.gossip());
+
+    Ok(node.run(handler).await?)
+}
@@ -1,19 +1,17 @@
-use gossip_glomers::{error::MaelstromError, Handler, MaelstromMessage, Node};
-use serde::{Deserialize, Serialize};
+use gossip_glomers::{error::MaelstromError, maelstrom_payload, Handler, MaelstromMessage, Node};
 
-#[derive(Deserialize, Clone, Debug)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum RequestPayload {
-    Echo { echo: String },
+maelstrom_payload! {
+    request enum RequestPayload {
+        Echo { echo: String },
+    }
 }
 
-#[derive(Serialize, Clone, Debug)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum ResponsePayload {
-    EchoOk { echo: String },
+maelstrom_payload! {
+    response enum ResponsePayload {
+        EchoOk { echo: String },
+    }
 }
 
-#[derive(Clone)]
 struct EchoHandler {
     node: Node,
 }
@@ -30,7 +28,7 @@ impl Handler<RequestPayload> for EchoHandler {
                     ResponsePayload::EchoOk {
                         echo: echo.to_string(),
                     },
-                );
+                )?;
             }
         }
 
@@ -40,7 +38,7 @@ impl Handler<RequestPayload> for EchoHandler {
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
-    let node = Node::init()?;
+    let node = Node::init().await?;
     let handler = EchoHandler { node: node.clone() };
     Ok(node.run(handler).await?)
 }
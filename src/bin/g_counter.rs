@@ -0,0 +1,315 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::SystemTime,
+};
+
+use gossip_glomers::{
+    error::MaelstromError, maelstrom_payload, parse_node_id, Handler, MaelstromMessage, Node,
+    NodeId, RawMessage,
+};
+use tokio::time::Duration;
+
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(100);
+// How old a peer's count may be before a read asks the peer for it directly
+const STALENESS_BOUND: Duration = Duration::from_secs(1);
+// Reads fall back to the cached count of a peer that doesn't answer in time
+const READ_LOCAL_TIMEOUT: Duration = Duration::from_millis(250);
+
+maelstrom_payload! {
+    message enum RequestPayload {
+        // Client requests
+        Add { delta: i64 },
+        Read,
+        // Every per-node total known to the sender. A list rather than a map,
+        // since integer map keys don't survive deserialization through a tagged enum.
+        Gossip { counters: Vec<(u32, i64)> },
+        // A peer's own count, for reads whose cached copy of it is stale
+        ReadLocal,
+    }
+}
+
+maelstrom_payload! {
+    message enum ResponsePayload {
+        ReadOk { value: i64 },
+        ReadLocalOk { value: i64 },
+    }
+}
+
+// Grow-only counter CRDT - every node only ever increments its own entry, so
+// entries can be merged by taking the maximum and the counter converges no
+// matter in which order (or how often) gossip is delivered
+//
+// Reads are served from the gossiped counts, as long as every peer has been
+// heard from within STALENESS_BOUND. Counts of peers we haven't heard from
+// are fetched with a read_local RPC first, so a read only misses adds older
+// than the bound if the peer doesn't answer either, e.g. during a partition.
+struct GCounterHandler {
+    node: Node,
+    counters: RwLock<BTreeMap<u32, i64>>,
+    // When each peer last told us its own count
+    heard: RwLock<BTreeMap<u32, SystemTime>>,
+    // Reads within the TTL of the last computed value get that value, and
+    // refresh it for later reads after replying. Only one read refreshes at a
+    // time, so a burst of reads costs a single round of read_local RPCs. Our
+    // own adds clear the cache, but adds on other nodes may take up to the
+    // TTL longer to show up. Set with READ_CACHE_TTL_MS, None to disable.
+    read_cache_ttl: Option<Duration>,
+    // Last computed value and when it was computed
+    read_cache: Mutex<Option<(SystemTime, i64)>>,
+    refreshing: AtomicBool,
+}
+
+impl GCounterHandler {
+    fn gossip(&self) {
+        let counters: Vec<_> = self
+            .counters
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(&node, &count)| (node, count))
+            .collect();
+        // Lost gossip is made up for by the next round
+        if let Err(e) = self
+            .node
+            .broadcast_to_peers(RequestPayload::Gossip { counters })
+        {
+            eprintln!("ERROR: Failed to gossip: {e}");
+        }
+    }
+
+    fn merge(&self, node: u32, count: i64) {
+        // Nobody knows our own count better than we do
+        if node != self.node.id {
+            let mut guard = self.counters.write().unwrap();
+            let entry = guard.entry(node).or_default();
+            *entry = (*entry).max(count);
+        }
+    }
+
+    fn stale_peers(&self) -> Vec<String> {
+        let now = self.node.now();
+        let heard = self.heard.read().unwrap();
+        self.node
+            .peers()
+            .into_iter()
+            .filter(|peer| {
+                parse_node_id(peer)
+                    .ok()
+                    .and_then(|id| heard.get(&id))
+                    .is_none_or(|&at| {
+                        now.duration_since(at)
+                            .is_ok_and(|age| age > STALENESS_BOUND)
+                    })
+            })
+            .collect()
+    }
+
+    // Sum of every node's count, after refreshing stale peers
+    async fn compute_read(&self) -> i64 {
+        self.refresh_stale().await;
+        // Summed under the cache lock, so an add can't clear the cache between
+        // us summing and storing a value without it
+        let mut cache = self.read_cache.lock().unwrap();
+        let value = self.counters.read().unwrap().values().sum();
+        if self.read_cache_ttl.is_some() {
+            *cache = Some((self.node.now(), value));
+        }
+        value
+    }
+
+    fn cached_read(&self) -> Option<i64> {
+        let ttl = self.read_cache_ttl?;
+        let (computed_at, value) = (*self.read_cache.lock().unwrap())?;
+        let age = self.node.now().duration_since(computed_at).ok()?;
+        (age <= ttl).then_some(value)
+    }
+
+    // Bring the counts of peers we haven't heard from lately up to date
+    async fn refresh_stale(&self) {
+        let stale = self.stale_peers();
+        if stale.is_empty() {
+            return;
+        }
+        // Peers that don't answer in time keep their cached count, it's the
+        // best we have
+        let responses = self
+            .node
+            .send_and_wait_all(&stale, RequestPayload::ReadLocal, READ_LOCAL_TIMEOUT)
+            .await;
+        for (peer, response) in responses {
+            match (parse_node_id(&peer), response) {
+                (Ok(id), ResponsePayload::ReadLocalOk { value }) => {
+                    self.merge(id, value);
+                    self.heard.write().unwrap().insert(id, self.node.now());
+                }
+                (_, response) => {
+                    eprintln!("ERROR: Unexpected response from {peer}: {response:?}");
+                }
+            }
+        }
+    }
+}
+
+impl Handler<RequestPayload> for GCounterHandler {
+    async fn handle(
+        &self,
+        counter_msg: &MaelstromMessage<RequestPayload>,
+    ) -> Result<(), MaelstromError> {
+        match &counter_msg.body.payload {
+            RequestPayload::Add { delta } => {
+                if *delta < 0 {
+                    return Err(MaelstromError::malformed_request("Counter can only grow"));
+                }
+                *self
+                    .counters
+                    .write()
+                    .unwrap()
+                    .entry(self.node.id)
+                    .or_default() += delta;
+                *self.read_cache.lock().unwrap() = None;
+                self.node.ack(counter_msg, "add_ok")?;
+            }
+            RequestPayload::Read => match self.cached_read() {
+                Some(value) => {
+                    self.node
+                        .reply(counter_msg, ResponsePayload::ReadOk { value })?;
+                    if !self.refreshing.swap(true, Ordering::SeqCst) {
+                        self.compute_read().await;
+                        self.refreshing.store(false, Ordering::SeqCst);
+                    }
+                }
+                None => {
+                    let value = self.compute_read().await;
+                    self.node
+                        .reply(counter_msg, ResponsePayload::ReadOk { value })?;
+                }
+            },
+            RequestPayload::Gossip { counters } => {
+                for &(node, count) in counters {
+                    self.merge(node, count);
+                }
+                // Gossip always carries the sender's own count
+                if let Ok(NodeId::Node(peer)) = counter_msg.src.parse() {
+                    self.heard.write().unwrap().insert(peer, self.node.now());
+                }
+            }
+            RequestPayload::ReadLocal => {
+                let value = self
+                    .counters
+                    .read()
+                    .unwrap()
+                    .get(&self.node.id)
+                    .copied()
+                    .unwrap_or_default();
+                self.node
+                    .reply(counter_msg, ResponsePayload::ReadLocalOk { value })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Garbage from a peer only costs us that peer's contribution until its
+    // next valid gossip, so log it instead of failing. Replying with an error
+    // would just hand the peer another message it doesn't understand.
+    async fn handle_unknown(&self, msg: &RawMessage) -> Result<(), MaelstromError> {
+        if let Ok(NodeId::Node(peer)) = msg.src.parse() {
+            eprintln!(
+                "ERROR: Ignoring invalid message from peer n{peer}: {}",
+                msg.body.payload
+            );
+            return Ok(());
+        }
+        Err(MaelstromError::not_supported(format!(
+            "Unsupported message: {}",
+            msg.body.payload
+        )))
+    }
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    let read_cache_ttl = match env::var("READ_CACHE_TTL_MS") {
+        Ok(ttl) => Some(Duration::from_millis(ttl.parse()?)),
+        Err(_) => None,
+    };
+
+    let node = Node::init().await?;
+    let handler = Arc::new(GCounterHandler {
+        node: node.clone(),
+        counters: RwLock::new(BTreeMap::new()),
+        heard: RwLock::new(BTreeMap::new()),
+        read_cache_ttl,
+        read_cache: Mutex::new(None),
+        refreshing: AtomicBool::new(false),
+    });
+
+    // Gossip until the node shuts down
+    let gossip_handler = Arc::clone(&handler);
+    node.spawn_periodic(GOSSIP_INTERVAL, move |_| gossip_handler.gossip());
+
+    Ok(node.run(handler).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers::node::testing::TestHarness;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn message(src: &str, body: &Value) -> String {
+        json!({"src": src, "dest": "n1", "body": body}).to_string()
+    }
+
+    #[tokio::test]
+    async fn invalid_peer_messages_count_as_nothing() {
+        let output = TestHarness::new("n1", vec!["n1".into(), "n2".into(), "n3".into()])
+            .input(message(
+                "c1",
+                &json!({"type": "add", "msg_id": 1, "delta": 5}),
+            ))
+            .input(message(
+                "n2",
+                &json!({"type": "gossip", "counters": [[2, 3]]}),
+            ))
+            .input(message(
+                "n3",
+                &json!({"type": "gossip", "counters": "garbage"}),
+            ))
+            .input(message("c1", &json!({"type": "read", "msg_id": 2})))
+            // n3 hasn't been heard from, so the read asks it for its count,
+            // which is msg 2 after init_ok and add_ok
+            .input(message(
+                "n3",
+                &json!({"type": "read_local_ok", "in_reply_to": 2, "value": "garbage"}),
+            ))
+            .run(|node| GCounterHandler {
+                node,
+                counters: RwLock::new(BTreeMap::new()),
+                heard: RwLock::new(BTreeMap::new()),
+                read_cache_ttl: None,
+                read_cache: Mutex::new(None),
+                refreshing: AtomicBool::new(false),
+            })
+            .await
+            .unwrap();
+        let output: Vec<Value> = output
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(output.len(), 4, "{output:#?}");
+        assert_eq!(output[1]["body"]["type"], "add_ok");
+        assert_eq!(output[2]["dest"], "n3");
+        assert_eq!(output[2]["body"]["type"], "read_local");
+        assert_eq!(output[2]["body"]["msg_id"], 2);
+        assert_eq!(output[3]["dest"], "c1");
+        assert_eq!(output[3]["body"]["type"], "read_ok");
+        assert_eq!(output[3]["body"]["value"], 8);
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    collections::BTreeMap,
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use gossip_glomers::{
+    error::{ErrorCode, GlomerError, MaelstromError},
+    kv_client::KvClient,
+    maelstrom_payload, Handler, MaelstromMessage, Node, NodeId,
+};
+
+// Maximum number of messages returned per key for a single poll
+const POLL_LIMIT: u64 = 10;
+
+maelstrom_payload! {
+    message enum RequestPayload {
+        Send { key: String, msg: u64 },
+        Poll { offsets: BTreeMap<String, u64> },
+        CommitOffsets { offsets: BTreeMap<String, u64> },
+        ListCommittedOffsets { keys: Vec<String> },
+    }
+}
+
+maelstrom_payload! {
+    // Responses are all named *Ok
+    #[allow(clippy::enum_variant_names)]
+    message enum ResponsePayload {
+        SendOk {
+            offset: u64,
+        },
+        PollOk {
+            msgs: BTreeMap<String, Vec<(u64, u64)>>,
+        },
+        ListCommittedOffsetsOk {
+            offsets: BTreeMap<String, u64>,
+        },
+    }
+}
+
+// Each log is stored in lin-kv as:
+//   next_offset_{key}      -> where to start looking for a free offset
+//   entry_{key}_{offset}   -> message stored at that offset
+//   committed_{key}        -> latest committed offset
+// Any node can handle any key. A send claims an offset by creating its entry
+// with a CAS, so concurrent sends from different nodes retry on the next
+// offset instead of being assigned the same one, and an offset is never taken
+// without its message, so the log has no holes. Since lin-kv is linearizable
+// a poll on one node observes every entry written by another before it
+// responded.
+//
+// Sends and polls are still routed to the key's owner, hash(key) % node count,
+// so only one node ever allocates offsets for a key and its CAS loop rarely
+// contends with another node's. Other nodes forward the request and relay the
+// owner's response. The owner is derived from the node ids in the init
+// message, which never change during a run, so keys are never rebalanced.
+// Since every log lives in lin-kv rather than on its owner, a different owner
+// would merely make offset allocation contend again, not lose messages.
+struct KafkaHandler {
+    node: Node,
+    client: KvClient,
+}
+
+// Errors from the owner are meant for the client as is
+fn forward_error(err: GlomerError) -> MaelstromError {
+    match err {
+        GlomerError::Maelstrom(err) => err,
+        // The owner may still have handled the request
+        GlomerError::Timeout => MaelstromError::timeout("Owner of the key didn't respond"),
+        err => err.into(),
+    }
+}
+
+const fn is_missing(err: &GlomerError) -> bool {
+    matches!(
+        err,
+        GlomerError::Maelstrom(MaelstromError {
+            code: ErrorCode::KeyDoesNotExist,
+            ..
+        })
+    )
+}
+
+// The CAS claiming an offset found an entry there already
+const fn is_taken(err: &GlomerError) -> bool {
+    matches!(
+        err,
+        GlomerError::CasConflict { .. }
+            | GlomerError::Maelstrom(MaelstromError {
+                code: ErrorCode::PreconditionFailed,
+                ..
+            })
+    )
+}
+
+impl KafkaHandler {
+    // Every node agrees on the owner, since DefaultHasher::new is unkeyed and
+    // node ids are the same everywhere
+    fn owner(&self, key: &str) -> &str {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let node_ids = &self.node.node_ids;
+        // The remainder always fits in a usize
+        #[allow(clippy::cast_possible_truncation)]
+        &node_ids[(hasher.finish() % node_ids.len() as u64) as usize]
+    }
+
+    // Requests forwarded by another node are always handled here, so nodes
+    // can't bounce a request between each other forever
+    fn is_local<P>(&self, key: &str, msg: &MaelstromMessage<P>) -> bool {
+        matches!(msg.src.parse(), Ok(NodeId::Node(_))) || self.owner(key) == self.node.id_str()
+    }
+
+    async fn forward(
+        &self,
+        owner: &str,
+        payload: RequestPayload,
+    ) -> Result<ResponsePayload, MaelstromError> {
+        self.node
+            .send_rpc(owner, payload, None)
+            .await
+            .map_err(forward_error)
+    }
+
+    async fn append(&self, key: &str, msg: u64) -> Result<u64, GlomerError> {
+        let hint_key = format!("next_offset_{key}");
+        let mut offset = u64::try_from(self.client.read_int_or(&hint_key, 0).await?)
+            .map_err(|e| GlomerError::Parse(e.to_string()))?;
+        let msg = serde_json::to_string(&msg)?;
+        loop {
+            // Creates the entry if the offset is free. Entries are never
+            // empty, so one that exists always fails the CAS.
+            match self
+                .client
+                .compare_and_swap(&format!("entry_{key}_{offset}"), "", &msg, true)
+                .await
+            {
+                Ok(()) => break,
+                Err(e) if is_taken(&e) => offset += 1,
+                Err(e) => return Err(e),
+            }
+        }
+        // Concurrent sends may leave the hint behind the end of the log, which
+        // only costs the next send a few more CASes. The message is stored
+        // either way, so failing here mustn't fail the send.
+        if let Err(e) = self.client.write_as(&hint_key, &(offset + 1)).await {
+            eprintln!("WARN: Failed to advance {hint_key}: {e}");
+        }
+        Ok(offset)
+    }
+
+    async fn read_log(&self, key: &str, from: u64) -> Result<Vec<(u64, u64)>, GlomerError> {
+        let entry_keys: Vec<_> = (from..from + POLL_LIMIT)
+            .map(|offset| format!("entry_{key}_{offset}"))
+            .collect();
+        let entry_keys: Vec<_> = entry_keys.iter().map(String::as_str).collect();
+        let mut msgs = Vec::new();
+        for (offset, (_, res)) in (from..).zip(self.client.read_many(&entry_keys).await) {
+            match res {
+                Ok(msg) => msgs.push((
+                    offset,
+                    serde_json::from_str(&msg).map_err(|e| GlomerError::Parse(e.to_string()))?,
+                )),
+                // The end of the log, offsets are only taken along with their
+                // message so there is nothing after it
+                Err(e) if is_missing(&e) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(msgs)
+    }
+}
+
+impl Handler<RequestPayload> for KafkaHandler {
+    async fn handle(
+        &self,
+        kafka_msg: &MaelstromMessage<RequestPayload>,
+    ) -> Result<(), MaelstromError> {
+        match &kafka_msg.body.payload {
+            RequestPayload::Send { key, msg } => {
+                if self.is_local(key, kafka_msg) {
+                    let offset = self.append(key, *msg).await?;
+                    self.node
+                        .reply(kafka_msg, ResponsePayload::SendOk { offset })?;
+                } else {
+                    let forwarded = RequestPayload::Send {
+                        key: key.clone(),
+                        msg: *msg,
+                    };
+                    match self.forward(self.owner(key), forwarded).await? {
+                        response @ ResponsePayload::SendOk { .. } => {
+                            self.node.reply(kafka_msg, response)?;
+                        }
+                        response => {
+                            return Err(
+                                GlomerError::unexpected_response(&response, "send_ok").into()
+                            )
+                        }
+                    }
+                }
+            }
+            RequestPayload::Poll { offsets } => {
+                let mut msgs = BTreeMap::new();
+                // Keys owned by other nodes, polled with one request per owner
+                let mut forwarded: BTreeMap<&str, BTreeMap<String, u64>> = BTreeMap::new();
+                for (key, &offset) in offsets {
+                    if self.is_local(key, kafka_msg) {
+                        msgs.insert(key.clone(), self.read_log(key, offset).await?);
+                    } else {
+                        forwarded
+                            .entry(self.owner(key))
+                            .or_default()
+                            .insert(key.clone(), offset);
+                    }
+                }
+                for (owner, offsets) in forwarded {
+                    match self
+                        .forward(owner, RequestPayload::Poll { offsets })
+                        .await?
+                    {
+                        ResponsePayload::PollOk { msgs: owned } => msgs.extend(owned),
+                        response => {
+                            return Err(
+                                GlomerError::unexpected_response(&response, "poll_ok").into()
+                            )
+                        }
+                    }
+                }
+                self.node
+                    .reply(kafka_msg, ResponsePayload::PollOk { msgs })?;
+            }
+            RequestPayload::CommitOffsets { offsets } => {
+                for (key, &offset) in offsets {
+                    let offset =
+                        i64::try_from(offset).map_err(|e| GlomerError::Parse(e.to_string()))?;
+                    // Committed offsets never move backwards
+                    self.client
+                        .update(&format!("committed_{key}"), offset, |committed| {
+                            committed.max(offset)
+                        })
+                        .await?;
+                }
+                self.node.ack(kafka_msg, "commit_offsets_ok")?;
+            }
+            RequestPayload::ListCommittedOffsets { keys } => {
+                let mut offsets = BTreeMap::new();
+                for key in keys {
+                    match self
+                        .client
+                        .read_as::<u64>(&format!("committed_{key}"))
+                        .await
+                    {
+                        Ok(offset) => {
+                            offsets.insert(key.clone(), offset);
+                        }
+                        // Nothing committed for this key yet
+                        Err(e) if is_missing(&e) => {}
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                self.node.reply(
+                    kafka_msg,
+                    ResponsePayload::ListCommittedOffsetsOk { offsets },
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    let node = Node::init().await?;
+    let handler = KafkaHandler {
+        node: node.clone(),
+        client: KvClient::lin(node.clone()),
+    };
+    Ok(node.run(handler).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers::node::testing::TestHarness;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn message(src: &str, body: &Value) -> String {
+        json!({"src": src, "dest": "n1", "body": body}).to_string()
+    }
+
+    #[tokio::test]
+    async fn send_claims_the_next_free_offset() {
+        // lin-kv's side of the conversation, msg_ids count up from init_ok
+        let output = TestHarness::new("n1", vec!["n1".into()])
+            .input(message(
+                "c1",
+                &json!({"type": "send", "msg_id": 1, "key": "k1", "msg": 7}),
+            ))
+            .input(message(
+                "lin-kv",
+                &json!({"type": "error", "in_reply_to": 1, "code": 20, "text": "not found"}),
+            ))
+            .input(message(
+                "lin-kv",
+                &json!({"type": "error", "in_reply_to": 2, "code": 22, "text": "taken"}),
+            ))
+            .input(message(
+                "lin-kv",
+                &json!({"type": "cas_ok", "in_reply_to": 3}),
+            ))
+            .input(message(
+                "lin-kv",
+                &json!({"type": "write_ok", "in_reply_to": 4}),
+            ))
+            .run(|node| KafkaHandler {
+                node: node.clone(),
+                client: KvClient::lin(node),
+            })
+            .await
+            .unwrap();
+        let output: Vec<Value> = output
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(output.len(), 6, "{output:#?}");
+        assert_eq!(output[1]["body"]["type"], "read");
+        assert_eq!(output[1]["body"]["key"], "next_offset_k1");
+        // Offset 0 is taken, so the message goes to offset 1
+        for (i, offset) in [(2, 0), (3, 1)] {
+            assert_eq!(output[i]["body"]["type"], "cas");
+            assert_eq!(output[i]["body"]["key"], format!("entry_k1_{offset}"));
+            assert_eq!(output[i]["body"]["to"], "7");
+            assert_eq!(output[i]["body"]["create_if_not_exists"], true);
+        }
+        assert_eq!(output[4]["body"]["type"], "write");
+        assert_eq!(output[4]["body"]["value"], "2");
+        assert_eq!(output[5]["body"]["type"], "send_ok");
+        assert_eq!(output[5]["body"]["offset"], 1);
+    }
+}
@@ -0,0 +1,62 @@
+use gossip_glomers::{
+    error::MaelstromError, kv_client::KvClient, maelstrom_payload, Handler, MaelstromMessage, Node,
+};
+
+maelstrom_payload! {
+    request enum RequestPayload {
+        Add { delta: i64 },
+        Read,
+    }
+}
+
+maelstrom_payload! {
+    response enum ResponsePayload {
+        ReadOk { value: i64 },
+    }
+}
+
+// Same counter as counter.rs, but backed by lin-kv. Since lin-kv reads are
+// linearizable, there's no need to write a read marker to force a fresh read.
+//
+// Compared to counter.rs, an add costs the same: one read and one compare and
+// swap, plus another round of both for every concurrent add it loses against,
+// since all nodes contend on the one key. A read takes a single round trip
+// instead of two, and always returns the latest value rather than one that may
+// lag behind this node's own adds.
+struct LinCounterHandler {
+    node: Node,
+    client: KvClient,
+}
+
+impl Handler<RequestPayload> for LinCounterHandler {
+    async fn handle(
+        &self,
+        counter_msg: &MaelstromMessage<RequestPayload>,
+    ) -> Result<(), MaelstromError> {
+        match counter_msg.body.payload {
+            RequestPayload::Add { delta } => {
+                self.client
+                    .update("counter", 0, |value| value + delta)
+                    .await?;
+                self.node.ack(counter_msg, "add_ok")?;
+            }
+            RequestPayload::Read => {
+                let value = self.client.read_int_or("counter", 0).await?;
+                self.node
+                    .reply(counter_msg, ResponsePayload::ReadOk { value })?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    let node = Node::init().await?;
+    let handler = LinCounterHandler {
+        node: node.clone(),
+        client: KvClient::lin(node.clone()),
+    };
+    Ok(node.run(handler).await?)
+}
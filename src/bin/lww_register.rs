@@ -0,0 +1,80 @@
+use gossip_glomers::{
+    error::{GlomerError, MaelstromError},
+    kv_client::KvClient,
+    maelstrom_payload, Handler, MaelstromMessage, Node,
+};
+
+// Serves Maelstrom's lin-kv workload straight from lww-kv, as an example of
+// storing a register and reading it back. lww-kv can serve stale reads, so
+// expect the linearizability checker to fail under partitions.
+maelstrom_payload! {
+    request enum RequestPayload {
+        Read { key: u64 },
+        Write { key: u64, value: i64 },
+        Cas { key: u64, from: i64, to: i64 },
+    }
+}
+
+maelstrom_payload! {
+    response enum ResponsePayload {
+        ReadOk { value: i64 },
+    }
+}
+
+struct RegisterHandler {
+    node: Node,
+    client: KvClient,
+}
+
+// Errors from lww-kv, e.g. KeyDoesNotExist, are meant for the client as is
+fn forward_error(err: GlomerError) -> MaelstromError {
+    match err {
+        GlomerError::Maelstrom(err) => err,
+        err => err.into(),
+    }
+}
+
+impl Handler<RequestPayload> for RegisterHandler {
+    async fn handle(
+        &self,
+        register_msg: &MaelstromMessage<RequestPayload>,
+    ) -> Result<(), MaelstromError> {
+        match register_msg.body.payload {
+            RequestPayload::Read { key } => {
+                let value = self
+                    .client
+                    .read_int(&key.to_string())
+                    .await
+                    .map_err(forward_error)?;
+                self.node
+                    .reply(register_msg, ResponsePayload::ReadOk { value })?;
+            }
+            RequestPayload::Write { key, value } => {
+                self.client
+                    .write_as(&key.to_string(), &value)
+                    .await
+                    .map_err(forward_error)?;
+                self.node.ack(register_msg, "write_ok")?;
+            }
+            RequestPayload::Cas { key, from, to } => {
+                self.client
+                    .compare_and_swap(&key.to_string(), &from.to_string(), &to.to_string(), false)
+                    .await
+                    .map_err(forward_error)?;
+                self.node.ack(register_msg, "cas_ok")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    let node = Node::init().await?;
+    let handler = RegisterHandler {
+        node: node.clone(),
+        client: KvClient::lww(node.clone()),
+    };
+    Ok(node.run(handler).await?)
+}
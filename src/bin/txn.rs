@@ -0,0 +1,179 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+
+use gossip_glomers::{error::MaelstromError, maelstrom_payload, Handler, MaelstromMessage, Node};
+use serde::{Deserialize, Serialize};
+
+// Micro-operation of a transaction, which is sent over the wire as a
+// ["r", key, value] or ["w", key, value] array
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(
+    try_from = "(String, u64, Option<u64>)",
+    into = "(&'static str, u64, Option<u64>)"
+)]
+enum Op {
+    Read { key: u64, value: Option<u64> },
+    Write { key: u64, value: u64 },
+}
+
+impl TryFrom<(String, u64, Option<u64>)> for Op {
+    type Error = String;
+
+    fn try_from((op, key, value): (String, u64, Option<u64>)) -> Result<Self, Self::Error> {
+        match (op.as_str(), value) {
+            ("r", value) => Ok(Self::Read { key, value }),
+            ("w", Some(value)) => Ok(Self::Write { key, value }),
+            ("w", None) => Err(format!("Write to {key} is missing a value")),
+            (op, _) => Err(format!("Unknown operation {op}")),
+        }
+    }
+}
+
+impl From<Op> for (&'static str, u64, Option<u64>) {
+    fn from(op: Op) -> Self {
+        match op {
+            Op::Read { key, value } => ("r", key, value),
+            Op::Write { key, value } => ("w", key, Some(value)),
+        }
+    }
+}
+
+maelstrom_payload! {
+    message enum RequestPayload {
+        // Client requests
+        Txn { txn: Vec<Op> },
+        // Committed writes from another node. A list rather than a map, since
+        // integer map keys don't survive deserialization through a tagged enum.
+        Replicate { writes: Vec<(u64, u64)> },
+    }
+}
+
+maelstrom_payload! {
+    response enum ResponsePayload {
+        TxnOk { txn: Vec<Op> },
+    }
+}
+
+// Read committed:
+// - Dirty reads are avoided by staging a transaction's writes in a buffer only
+//   visible to that transaction. Other transactions can only ever observe the
+//   store, which holds nothing but committed values.
+// - Commits install the whole write set under a single write lock, and all
+//   reads of a transaction happen under a single read lock, so concurrent
+//   transactions never observe half of another's writes, and only the final
+//   value of a key written several times within a transaction.
+// - Lost updates are allowed under read committed. Concurrent transactions on
+//   different nodes writing the same key are resolved by whichever write set is
+//   applied last, locally or through replication.
+struct TxnHandler {
+    node: Node,
+    store: Arc<RwLock<BTreeMap<u64, u64>>>,
+}
+
+impl TxnHandler {
+    // Send committed writes to every other node, no need to wait for them
+    fn replicate(&self, writes: &BTreeMap<u64, u64>) -> Result<(), MaelstromError> {
+        let writes = writes.iter().map(|(&key, &value)| (key, value)).collect();
+        self.node
+            .broadcast_to_peers(RequestPayload::Replicate { writes })?;
+        Ok(())
+    }
+}
+
+impl Handler<RequestPayload> for TxnHandler {
+    async fn handle(
+        &self,
+        txn_msg: &MaelstromMessage<RequestPayload>,
+    ) -> Result<(), MaelstromError> {
+        match &txn_msg.body.payload {
+            RequestPayload::Txn { txn } => {
+                let mut staged = BTreeMap::new();
+                let txn = {
+                    // A commit can't land between two of our reads
+                    let store = self.store.read().unwrap();
+                    txn.iter()
+                        .map(|op| match *op {
+                            Op::Read { key, .. } => Op::Read {
+                                key,
+                                // Our own writes first, otherwise the latest committed value
+                                value: staged.get(&key).or(store.get(&key)).copied(),
+                            },
+                            Op::Write { key, value } => {
+                                staged.insert(key, value);
+                                Op::Write { key, value }
+                            }
+                        })
+                        .collect()
+                };
+
+                // Commit, publishing all of the writes at once
+                if !staged.is_empty() {
+                    self.store.write().unwrap().extend(&staged);
+                    self.replicate(&staged)?;
+                }
+                self.node.reply(txn_msg, ResponsePayload::TxnOk { txn })?;
+            }
+            RequestPayload::Replicate { writes } => {
+                self.store.write().unwrap().extend(writes.iter().copied());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    let node = Node::init().await?;
+    let handler = TxnHandler {
+        node: node.clone(),
+        store: Arc::new(RwLock::new(BTreeMap::new())),
+    };
+    Ok(node.run(handler).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use gossip_glomers::node::testing::TestHarness;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn txn_reads_its_own_and_committed_writes() {
+        let txn = |msg_id: u64, txn: Value| {
+            json!({
+                "src": "c1",
+                "dest": "n1",
+                "body": {"type": "txn", "msg_id": msg_id, "txn": txn},
+            })
+            .to_string()
+        };
+        let output = TestHarness::new("n1", vec!["n1".into()])
+            .input(txn(
+                1,
+                json!([["r", 1, null], ["w", 1, 5], ["r", 1, null], ["r", 2, null]]),
+            ))
+            .input(txn(2, json!([["r", 1, null]])))
+            .run(|node| TxnHandler {
+                node,
+                store: Arc::default(),
+            })
+            .await
+            .unwrap();
+        let replies: Vec<Value> = output
+            .iter()
+            .skip(1)
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(replies.len(), 2, "{output:#?}");
+        assert_eq!(replies[0]["body"]["type"], "txn_ok");
+        assert_eq!(
+            replies[0]["body"]["txn"],
+            json!([["r", 1, null], ["w", 1, 5], ["r", 1, 5], ["r", 2, null]])
+        );
+        assert_eq!(replies[1]["body"]["txn"], json!([["r", 1, 5]]));
+    }
+}
@@ -1,31 +1,87 @@
-use gossip_glomers::{error::MaelstromError, Handler, MaelstromMessage, Node};
-use serde::{Deserialize, Serialize};
-use uuid::Uuid;
+use std::{
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-#[derive(Deserialize, Clone, Debug)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum RequestPayload {
-    Generate,
+use gossip_glomers::{error::MaelstromError, maelstrom_payload, Handler, MaelstromMessage, Node};
+use uuid::{Timestamp, Uuid};
+
+maelstrom_payload! {
+    request enum RequestPayload {
+        Generate,
+    }
+}
+
+maelstrom_payload! {
+    response enum ResponsePayload {
+        GenerateOk { id: Uuid },
+    }
+}
+
+// Offset of the Unix epoch from the Gregorian epoch UUID timestamps count
+// from, in 100ns ticks
+const GREGORIAN_OFFSET: u64 = 0x01B2_1DD2_1381_4000;
+
+// Number of distinct values of the 14 bit clock sequence
+const CLOCK_SEQ_VALUES: u16 = 1 << 14;
+
+// Counts the ids generated within the same 100ns tick and mixes the count into
+// the clock sequence, so ids handed out concurrently never repeat. The
+// timestamp is the node's time, only moved on should all 16384 clock
+// sequences of a tick be used up, e.g. with a clock that stands still.
+struct IdSequence {
+    // Timestamp of the last id, in 100ns ticks since the Gregorian epoch, and
+    // how many ids before it had the same timestamp
+    last: Mutex<(u64, u16)>,
+    // Added to the count, random per process so that a restarted node is
+    // unlikely to repeat the ids it handed out before, even within the same
+    // ticks. Tests pass a fixed one.
+    base: u16,
 }
 
-#[derive(Serialize, Clone, Debug)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum ResponsePayload {
-    GenerateOk { id: Uuid },
+impl IdSequence {
+    fn new(base: u16) -> Self {
+        Self {
+            last: Mutex::new((0, 0)),
+            base: base % CLOCK_SEQ_VALUES,
+        }
+    }
+
+    // Timestamp and clock sequence for the next id
+    fn next(&self, now: SystemTime) -> (u64, u16) {
+        let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let now_tick = u64::try_from(since_epoch.as_nanos() / 100)
+            .unwrap_or(u64::MAX)
+            .saturating_add(GREGORIAN_OFFSET);
+        let mut last = self.last.lock().unwrap();
+        *last = match *last {
+            (tick, _) if now_tick > tick => (now_tick, 0),
+            // Same tick, or the clock went backwards
+            (tick, count) if count + 1 < CLOCK_SEQ_VALUES => (tick, count + 1),
+            (tick, _) => (tick + 1, 0),
+        };
+        let (tick, count) = *last;
+        (tick, (self.base + count) % CLOCK_SEQ_VALUES)
+    }
 }
 
-fn make_uuid(node_id: u32) -> Uuid {
+fn make_uuid(node_id: u32, now: SystemTime, sequence: &IdSequence) -> Uuid {
     // Uuid v6 expects 6 bytes for the node ID. We are assuming 4 bytes for our
     // NodeIDs, so the last 4 characters of the GUID will always be '0000'.
     // This optimizes down to a single mov on x86 :)
     let array = node_id.to_le_bytes();
-    // Use UUID v6 for unique ID. It uses current timestamp and NodeID
-    // for input, so it is guaranteed to be unique per message per node.
-    Uuid::now_v6(&[array[0], array[1], array[2], array[3], 0, 0])
+    // Use UUID v6 for unique ID. It uses a timestamp and clock sequence pair
+    // that never repeats on this node and the NodeID for input, so it is
+    // guaranteed to be unique per message per node. The time comes from the
+    // node, so tests control it.
+    let (tick, clock_seq) = sequence.next(now);
+    let timestamp = Timestamp::from_gregorian(tick, clock_seq);
+    Uuid::new_v6(timestamp, &[array[0], array[1], array[2], array[3], 0, 0])
 }
 
 struct UniqueIdHandler {
     node: Node,
+    sequence: IdSequence,
 }
 
 impl Handler<RequestPayload> for UniqueIdHandler {
@@ -38,9 +94,9 @@ impl Handler<RequestPayload> for UniqueIdHandler {
                 self.node.reply(
                     generate_msg,
                     ResponsePayload::GenerateOk {
-                        id: make_uuid(self.node.id),
+                        id: make_uuid(self.node.id, self.node.now(), &self.sequence),
                     },
-                );
+                )?;
             }
         }
 
@@ -50,7 +106,72 @@ impl Handler<RequestPayload> for UniqueIdHandler {
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
-    let node = Node::init()?;
-    let handler = UniqueIdHandler { node: node.clone() };
+    let node = Node::init().await?;
+    let handler = UniqueIdHandler {
+        node: node.clone(),
+        sequence: IdSequence::new(rand::random()),
+    };
     Ok(node.run(handler).await?)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, thread, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn concurrent_ids_are_unique() {
+        const THREADS: usize = 8;
+        const IDS_PER_THREAD: usize = 10_000;
+        let sequence = IdSequence::new(0);
+        // A clock that stands still, so every id is generated in the same tick
+        let now = SystemTime::now();
+        let ids: Vec<Uuid> = thread::scope(|scope| {
+            let threads: Vec<_> = (0..THREADS)
+                .map(|_| {
+                    scope.spawn(|| {
+                        (0..IDS_PER_THREAD)
+                            .map(|_| make_uuid(1, now, &sequence))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .flat_map(|thread| thread.join().unwrap())
+                .collect()
+        });
+        let unique: BTreeSet<_> = ids.iter().collect();
+        assert_eq!(unique.len(), THREADS * IDS_PER_THREAD);
+    }
+
+    #[test]
+    fn ids_differ_between_nodes() {
+        let now = SystemTime::now();
+        let (a, b) = (IdSequence::new(0), IdSequence::new(0));
+        assert_ne!(make_uuid(1, now, &a), make_uuid(2, now, &b));
+    }
+
+    #[test]
+    fn ids_are_deterministic() {
+        let now = SystemTime::now();
+        let ids = || {
+            let sequence = IdSequence::new(3);
+            [make_uuid(3, now, &sequence), make_uuid(3, now, &sequence)]
+        };
+        assert_eq!(ids(), ids());
+    }
+
+    #[test]
+    fn ids_in_the_same_tick_keep_the_time() {
+        let now = SystemTime::now();
+        let sequence = IdSequence::new(CLOCK_SEQ_VALUES - 1);
+        let (a, b) = (sequence.next(now), sequence.next(now));
+        assert_eq!(a.0, b.0);
+        // The count wraps around along with the base
+        assert_eq!((a.1, b.1), (CLOCK_SEQ_VALUES - 1, 0));
+        let later = sequence.next(now + Duration::from_micros(1));
+        assert_eq!(later, (a.0 + 10, CLOCK_SEQ_VALUES - 1));
+    }
+}
@@ -0,0 +1,56 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// Wall clock time as seen by a node, see Node::now. Tests can swap in a mock
+// clock, which only moves when told to, so anything derived from timestamps
+// (e.g. unique ids) comes out the same on every run.
+#[derive(Debug, Clone, Default)]
+pub enum Clock {
+    #[default]
+    System,
+    // Nanoseconds since the Unix epoch, shared by every clone of the clock
+    Mock(Arc<AtomicU64>),
+}
+
+impl Clock {
+    // A mock clock starting at start, which must be after the Unix epoch
+    #[must_use]
+    pub fn mock(start: SystemTime) -> Self {
+        let clock = Self::Mock(Arc::new(AtomicU64::new(0)));
+        clock.set(start);
+        clock
+    }
+
+    #[must_use]
+    pub fn now(&self) -> SystemTime {
+        match self {
+            Self::System => SystemTime::now(),
+            Self::Mock(nanos) => UNIX_EPOCH + Duration::from_nanos(nanos.load(Ordering::SeqCst)),
+        }
+    }
+
+    // Move a mock clock forward, no effect on the system clock
+    pub fn advance(&self, duration: Duration) {
+        if let Self::Mock(nanos) = self {
+            nanos.fetch_add(saturating_nanos(duration), Ordering::SeqCst);
+        }
+    }
+
+    // Set a mock clock to time, no effect on the system clock
+    pub fn set(&self, time: SystemTime) {
+        if let Self::Mock(nanos) = self {
+            let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            nanos.store(saturating_nanos(since_epoch), Ordering::SeqCst);
+        }
+    }
+}
+
+// More than enough for the next few centuries
+fn saturating_nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
@@ -2,6 +2,7 @@ use std::{fmt, io};
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tokio::time::error::Elapsed;
 
 #[allow(clippy::module_name_repetitions)]
 #[derive(Error, Debug)]
@@ -26,6 +27,30 @@ pub enum GlomerError {
 
     #[error("{0}")]
     Abort(String),
+
+    // The RPC was abandoned before its response arrived, either with
+    // Node::cancel_rpc or because the node is shutting down
+    #[error("RPC was cancelled")]
+    Cancelled,
+
+    // A compare and swap failed because the key holds a different value
+    #[error("Compare and swap failed, current value is {current}")]
+    CasConflict { current: String },
+
+    // A response that isn't what the request should have been answered with,
+    // raw is the JSON payload as received
+    #[error("Unexpected response, expected {expected}: {raw}")]
+    UnexpectedResponse { raw: String, expected: &'static str },
+}
+
+impl GlomerError {
+    // UnexpectedResponse for a response that has already been parsed
+    pub fn unexpected_response(response: &impl Serialize, expected: &'static str) -> Self {
+        Self::UnexpectedResponse {
+            raw: serde_json::to_string(response).unwrap_or_else(|e| e.to_string()),
+            expected,
+        }
+    }
 }
 
 #[allow(clippy::module_name_repetitions)]
@@ -33,14 +58,25 @@ pub enum GlomerError {
 #[serde(tag = "type", rename = "error")]
 pub struct MaelstromError {
     pub text: String,
-    pub code: u32,
+    pub code: ErrorCode,
+}
+
+// Lets ? turn an expired tokio::time::timeout into GlomerError::Timeout
+impl From<Elapsed> for GlomerError {
+    fn from(_: Elapsed) -> Self {
+        Self::Timeout
+    }
 }
 
 impl From<GlomerError> for MaelstromError {
     fn from(err: GlomerError) -> Self {
+        let code = match err {
+            GlomerError::CasConflict { .. } => ErrorCode::PreconditionFailed,
+            _ => ErrorCode::Abort,
+        };
         Self {
             text: err.to_string(),
-            code: error_type::ABORT,
+            code,
         }
     }
 }
@@ -48,23 +84,90 @@ impl From<GlomerError> for MaelstromError {
 impl fmt::Display for MaelstromError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let code_name = match self.code {
-            error_type::TIMEOUT => "Timeout",
-            error_type::NODE_NOT_FOUND => "NodeNotFound",
-            error_type::NOT_SUPPORTED => "NotSupported",
-            error_type::TEMPORARILY_UNAVAILABLE => "TemporarilyUnavailable",
-            error_type::MALFORMED_REQUEST => "MalformedRequest",
-            error_type::CRASH => "Crash",
-            error_type::ABORT => "Abort",
-            error_type::KEY_DOES_NOT_EXIST => "KeyDoesNotExist",
-            error_type::KEY_ALREADY_EXISTS => "KeyAlreadyExists",
-            error_type::PRECONDITION_FAILED => "PreconditionFailed",
-            error_type::TXN_CONFLICT => "TxnConflict",
-            _ => "Unknown",
+            ErrorCode::Timeout => "Timeout",
+            ErrorCode::NodeNotFound => "NodeNotFound",
+            ErrorCode::NotSupported => "NotSupported",
+            ErrorCode::TemporarilyUnavailable => "TemporarilyUnavailable",
+            ErrorCode::MalformedRequest => "MalformedRequest",
+            ErrorCode::Crash => "Crash",
+            ErrorCode::Abort => "Abort",
+            ErrorCode::KeyDoesNotExist => "KeyDoesNotExist",
+            ErrorCode::KeyAlreadyExists => "KeyAlreadyExists",
+            ErrorCode::PreconditionFailed => "PreconditionFailed",
+            ErrorCode::TxnConflict => "TxnConflict",
+            ErrorCode::Custom(code) => {
+                return write!(f, "Error: Unknown ({}): '{}'", code, self.text)
+            }
         };
         write!(f, "Error: {}: '{}'", code_name, self.text)
     }
 }
 
+// Error codes as sent over the wire, anything Maelstrom doesn't define
+// ends up in Custom
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(from = "u32", into = "u32")]
+pub enum ErrorCode {
+    Timeout,
+    NodeNotFound,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed,
+    TxnConflict,
+    Custom(u32),
+}
+
+impl ErrorCode {
+    #[must_use]
+    pub const fn raw(self) -> u32 {
+        match self {
+            Self::Timeout => error_type::TIMEOUT,
+            Self::NodeNotFound => error_type::NODE_NOT_FOUND,
+            Self::NotSupported => error_type::NOT_SUPPORTED,
+            Self::TemporarilyUnavailable => error_type::TEMPORARILY_UNAVAILABLE,
+            Self::MalformedRequest => error_type::MALFORMED_REQUEST,
+            Self::Crash => error_type::CRASH,
+            Self::Abort => error_type::ABORT,
+            Self::KeyDoesNotExist => error_type::KEY_DOES_NOT_EXIST,
+            Self::KeyAlreadyExists => error_type::KEY_ALREADY_EXISTS,
+            Self::PreconditionFailed => error_type::PRECONDITION_FAILED,
+            Self::TxnConflict => error_type::TXN_CONFLICT,
+            Self::Custom(code) => code,
+        }
+    }
+}
+
+impl From<u32> for ErrorCode {
+    fn from(code: u32) -> Self {
+        match code {
+            error_type::TIMEOUT => Self::Timeout,
+            error_type::NODE_NOT_FOUND => Self::NodeNotFound,
+            error_type::NOT_SUPPORTED => Self::NotSupported,
+            error_type::TEMPORARILY_UNAVAILABLE => Self::TemporarilyUnavailable,
+            error_type::MALFORMED_REQUEST => Self::MalformedRequest,
+            error_type::CRASH => Self::Crash,
+            error_type::ABORT => Self::Abort,
+            error_type::KEY_DOES_NOT_EXIST => Self::KeyDoesNotExist,
+            error_type::KEY_ALREADY_EXISTS => Self::KeyAlreadyExists,
+            error_type::PRECONDITION_FAILED => Self::PreconditionFailed,
+            error_type::TXN_CONFLICT => Self::TxnConflict,
+            code => Self::Custom(code),
+        }
+    }
+}
+
+impl From<ErrorCode> for u32 {
+    fn from(code: ErrorCode) -> Self {
+        code.raw()
+    }
+}
+
+// Raw codes of the errors defined by Maelstrom
 #[allow(clippy::module_name_repetitions)]
 pub mod error_type {
     pub const TIMEOUT: u32 = 0;
@@ -82,80 +185,156 @@ pub mod error_type {
 
 #[allow(dead_code)]
 impl MaelstromError {
+    // Workload specific errors, Maelstrom reserves codes below 1000
+    pub fn custom(code: u32, error_text: impl Into<String>) -> Self {
+        Self {
+            text: error_text.into(),
+            code: code.into(),
+        }
+    }
+
     pub fn timeout(error_text: impl Into<String>) -> Self {
         Self {
             text: error_text.into(),
-            code: error_type::TIMEOUT,
+            code: ErrorCode::Timeout,
         }
     }
 
     pub fn node_not_found(error_text: impl Into<String>) -> Self {
         Self {
             text: error_text.into(),
-            code: error_type::NODE_NOT_FOUND,
+            code: ErrorCode::NodeNotFound,
         }
     }
 
     pub fn not_supported(error_text: impl Into<String>) -> Self {
         Self {
             text: error_text.into(),
-            code: error_type::NOT_SUPPORTED,
+            code: ErrorCode::NotSupported,
         }
     }
 
     pub fn temporarily_unavailable(error_text: impl Into<String>) -> Self {
         Self {
             text: error_text.into(),
-            code: error_type::TEMPORARILY_UNAVAILABLE,
+            code: ErrorCode::TemporarilyUnavailable,
         }
     }
 
     pub fn malformed_request(error_text: impl Into<String>) -> Self {
         Self {
             text: error_text.into(),
-            code: error_type::MALFORMED_REQUEST,
+            code: ErrorCode::MalformedRequest,
         }
     }
 
     pub fn crash(error_text: impl Into<String>) -> Self {
         Self {
             text: error_text.into(),
-            code: error_type::CRASH,
+            code: ErrorCode::Crash,
         }
     }
 
     pub fn abort(error_text: impl Into<String>) -> Self {
         Self {
             text: error_text.into(),
-            code: error_type::ABORT,
+            code: ErrorCode::Abort,
         }
     }
 
     pub fn key_does_not_exist(error_text: impl Into<String>) -> Self {
         Self {
             text: error_text.into(),
-            code: error_type::KEY_DOES_NOT_EXIST,
+            code: ErrorCode::KeyDoesNotExist,
         }
     }
 
     pub fn key_already_exists(error_text: impl Into<String>) -> Self {
         Self {
             text: error_text.into(),
-            code: error_type::KEY_ALREADY_EXISTS,
+            code: ErrorCode::KeyAlreadyExists,
         }
     }
 
     pub fn precondition_failed(error_text: impl Into<String>) -> Self {
         Self {
             text: error_text.into(),
-            code: error_type::PRECONDITION_FAILED,
+            code: ErrorCode::PreconditionFailed,
         }
     }
 
     pub fn txn_conflict(error_text: impl Into<String>) -> Self {
         Self {
             text: error_text.into(),
-            code: error_type::TXN_CONFLICT,
+            code: ErrorCode::TxnConflict,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use tokio::time::{timeout, Duration};
+
+    use super::{ErrorCode, GlomerError, MaelstromError};
+
+    #[test]
+    fn display() {
+        assert_eq!(GlomerError::Timeout.to_string(), "Operation timed out");
+        assert_eq!(
+            GlomerError::Parse("bad id".into()).to_string(),
+            "Unable to parse message: bad id"
+        );
+        assert_eq!(GlomerError::Abort("gone".into()).to_string(), "gone");
+        assert_eq!(GlomerError::Cancelled.to_string(), "RPC was cancelled");
+        assert_eq!(
+            MaelstromError::key_does_not_exist("no foo").to_string(),
+            "Error: KeyDoesNotExist: 'no foo'"
+        );
+        assert_eq!(
+            MaelstromError::custom(1001, "odd").to_string(),
+            "Error: Unknown (1001): 'odd'"
+        );
+        // Transparent, so the message is the same as the wrapped error's
+        let err = MaelstromError::abort("failed");
+        assert_eq!(GlomerError::from(err.clone()).to_string(), err.to_string());
+    }
+
+    #[test]
+    fn io_error_converts() {
+        fn read() -> Result<(), GlomerError> {
+            Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stdin closed"))?
+        }
+        let err = read().unwrap_err();
+        assert_eq!(err.to_string(), "stdin closed");
+        assert!(matches!(err, GlomerError::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof));
+    }
+
+    #[tokio::test]
+    async fn elapsed_converts_to_timeout() {
+        let res = timeout(Duration::ZERO, std::future::pending::<()>()).await;
+        assert!(matches!(
+            res.map_err(GlomerError::from),
+            Err(GlomerError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn maelstrom_error_conversions() {
+        let err = MaelstromError::from(GlomerError::Timeout);
+        assert_eq!(err.code, ErrorCode::Abort);
+        let err = MaelstromError::from(GlomerError::CasConflict {
+            current: "1".into(),
+        });
+        assert_eq!(err.code, ErrorCode::PreconditionFailed);
+        let glomer = GlomerError::from(err);
+        assert!(matches!(
+            glomer,
+            GlomerError::Maelstrom(MaelstromError {
+                code: ErrorCode::PreconditionFailed,
+                ..
+            })
+        ));
+    }
+}
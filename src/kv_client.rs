@@ -0,0 +1,445 @@
+use std::panic;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::task::JoinSet;
+
+use crate::{
+    error::{ErrorCode, GlomerError, MaelstromError},
+    node::Node,
+    retry::RetryPolicy,
+};
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RequestPayload<'a> {
+    Read {
+        key: &'a str,
+    },
+    Write {
+        key: &'a str,
+        value: &'a str,
+    },
+    #[serde(rename = "cas")]
+    CompareAndSwap {
+        key: &'a str,
+        from: &'a str,
+        to: &'a str,
+        create_if_not_exists: bool,
+    },
+    Delete {
+        key: &'a str,
+    },
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponsePayload {
+    ReadOk {
+        value: String,
+    },
+    WriteOk,
+    #[serde(rename = "cas_ok")]
+    CompareAndSwapOk,
+    DeleteOk,
+}
+
+// Key-value stores provided by Maelstrom, which only differ in their
+// consistency guarantees
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KvService {
+    // Sequentially consistent
+    Seq,
+    // Linearizable: a read always observes the latest completed write, at
+    // the cost of higher latency than seq-kv
+    Lin,
+    // Last-write-wins registers: concurrent writes are resolved by keeping
+    // the one with the latest timestamp, and reads may return stale values
+    // for a while. Unlike seq-kv there is no single order all nodes agree on,
+    // so a compare and swap only compares against the value this replica has
+    // seen.
+    Lww,
+}
+
+impl KvService {
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Seq => "seq-kv",
+            Self::Lin => "lin-kv",
+            Self::Lww => "lww-kv",
+        }
+    }
+}
+
+// A client needs a new node templated on the message protocol
+// for the Maelstrom kv services
+#[derive(Clone)]
+pub struct KvClient {
+    node: Node,
+    name: &'static str,
+    retry: Option<RetryPolicy>,
+}
+
+impl KvClient {
+    #[must_use]
+    pub const fn new(node: Node, service: KvService) -> Self {
+        Self {
+            node,
+            name: service.name(),
+            retry: None,
+        }
+    }
+
+    // Client for seq-kv, same as KvClient::new(node, KvService::Seq)
+    #[must_use]
+    pub const fn seq(node: Node) -> Self {
+        Self::new(node, KvService::Seq)
+    }
+
+    // Client for lin-kv, same as KvClient::new(node, KvService::Lin)
+    #[must_use]
+    pub const fn lin(node: Node) -> Self {
+        Self::new(node, KvService::Lin)
+    }
+
+    // Client for lww-kv, same as KvClient::new(node, KvService::Lww)
+    #[must_use]
+    pub const fn lww(node: Node) -> Self {
+        Self::new(node, KvService::Lww)
+    }
+
+    // Transparently retry requests that fail with one of the policy's
+    // retryable codes, only surfacing the error once all attempts are used up.
+    // Use retryable_codes: vec![ErrorCode::TemporarilyUnavailable] to only
+    // retry when the service asks for it; also retrying timeouts (as
+    // RetryPolicy::default does) means a write or compare and swap may be
+    // applied twice, or fail with PreconditionFailed after it has succeeded.
+    #[must_use]
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    async fn rpc(&self, payload: RequestPayload<'_>) -> Result<ResponsePayload, GlomerError> {
+        match &self.retry {
+            Some(policy) => self.node.send_rpc_retry(self.name, payload, policy).await,
+            None => self.node.send_rpc(self.name, payload, None).await,
+        }
+    }
+
+    pub async fn read(&self, key: &str) -> Result<String, GlomerError> {
+        // Issue a read reqeust to the kv service and return the response
+        let response = self.rpc(RequestPayload::Read { key }).await;
+        match response {
+            Ok(ResponsePayload::ReadOk { value }) => Ok(value),
+            Ok(response) => Err(GlomerError::unexpected_response(&response, "read_ok")),
+            // Includes KeyDoesNotExist
+            Err(e) => Err(e),
+        }
+    }
+
+    // Same as read, with None for a key that doesn't exist
+    pub async fn read_opt(&self, key: &str) -> Result<Option<String>, GlomerError> {
+        match self.read(key).await {
+            Ok(value) => Ok(Some(value)),
+            Err(GlomerError::Maelstrom(MaelstromError {
+                code: ErrorCode::KeyDoesNotExist,
+                ..
+            })) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Read several keys concurrently. Results are in the same order as keys,
+    // each with its own error, so e.g. a missing key doesn't fail the others.
+    pub async fn read_many(&self, keys: &[&str]) -> Vec<(String, Result<String, GlomerError>)> {
+        let mut reads = JoinSet::new();
+        for (i, &key) in keys.iter().enumerate() {
+            let client = self.clone();
+            let key = key.to_string();
+            reads.spawn(async move {
+                let res = client.read(&key).await;
+                (i, key, res)
+            });
+        }
+        let mut results = Vec::with_capacity(keys.len());
+        while let Some(res) = reads.join_next().await {
+            match res {
+                Ok(result) => results.push(result),
+                Err(err) => panic::resume_unwind(err.into_panic()),
+            }
+        }
+        results.sort_unstable_by_key(|&(i, ..)| i);
+        results
+            .into_iter()
+            .map(|(_, key, res)| (key, res))
+            .collect()
+    }
+
+    // Read a value that was stored with write_as
+    pub async fn read_as<T>(&self, key: &str) -> Result<T, GlomerError>
+    where
+        T: DeserializeOwned,
+    {
+        let value = self.read(key).await?;
+        serde_json::from_str(&value)
+            // TODO Parse error implies error in parsing message
+            // this is an error in parsing something that we stored internally,
+            // trying to read a T from something that is not a T
+            .map_err(|e| GlomerError::Parse(e.to_string()))
+    }
+
+    pub async fn read_int(&self, key: &str) -> Result<i64, GlomerError> {
+        self.read_as::<i64>(key).await
+    }
+
+    pub async fn write(&self, key: &str, value: &str) -> Result<(), GlomerError> {
+        let response = self.rpc(RequestPayload::Write { key, value }).await;
+        match response {
+            Ok(ResponsePayload::WriteOk) => Ok(()),
+            Ok(response) => Err(GlomerError::unexpected_response(&response, "write_ok")),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Store any serializable value, JSON encoded
+    pub async fn write_as<T>(&self, key: &str, value: &T) -> Result<(), GlomerError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.write(key, &serde_json::to_string(value)?).await
+    }
+
+    pub async fn compare_and_swap(
+        &self,
+        key: &str,
+        from: &str,
+        to: &str,
+        create_if_not_exists: bool,
+    ) -> Result<(), GlomerError> {
+        let response = self
+            .rpc(RequestPayload::CompareAndSwap {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            })
+            .await;
+        match response {
+            Ok(ResponsePayload::CompareAndSwapOk) => Ok(()),
+            Err(GlomerError::Maelstrom(MaelstromError {
+                code: ErrorCode::PreconditionFailed,
+                text,
+            })) => match parse_current_value(&text) {
+                Some(current) => Err(GlomerError::CasConflict { current }),
+                None => Err(GlomerError::Maelstrom(MaelstromError::precondition_failed(
+                    text,
+                ))),
+            },
+            Ok(response) => Err(GlomerError::unexpected_response(&response, "cas_ok")),
+            // Includes KeyDoesNotExist when not creating the key
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<(), GlomerError> {
+        let response = self.rpc(RequestPayload::Delete { key }).await;
+        match response {
+            Ok(ResponsePayload::DeleteOk) => Ok(()),
+            Ok(response) => Err(GlomerError::unexpected_response(&response, "delete_ok")),
+            // Includes KeyDoesNotExist, and NotSupported from services without delete
+            Err(e) => Err(e),
+        }
+    }
+
+    // Atomically apply f to the integer stored at key, treating a missing key
+    // as default. Retries with the fresh value whenever another writer got in
+    // between our read and our compare and swap. Returns the value written.
+    pub async fn update<F>(&self, key: &str, default: i64, f: F) -> Result<i64, GlomerError>
+    where
+        F: Fn(i64) -> i64,
+    {
+        let mut current_value = self.read_int_or(key, default).await?;
+        loop {
+            let new_value = f(current_value);
+            let res = self
+                .compare_and_swap(
+                    key,
+                    &current_value.to_string(),
+                    &new_value.to_string(),
+                    true,
+                )
+                .await;
+            current_value = match res {
+                Ok(()) => return Ok(new_value),
+                // The service told us what we lost against, no need to read it
+                Err(GlomerError::CasConflict { current }) => {
+                    serde_json::from_str(&current).map_err(|e| GlomerError::Parse(e.to_string()))?
+                }
+                Err(GlomerError::Maelstrom(MaelstromError {
+                    code: ErrorCode::PreconditionFailed,
+                    ..
+                })) => self.read_int_or(key, default).await?,
+                Err(e) => return Err(e),
+            };
+        }
+    }
+
+    // Versioned values, for reading a consistent snapshot at a timestamp, e.g.
+    // one from LinTsoClient. The key holds every version ever written as a
+    // JSON list of [ts, value] pairs, oldest first, so reads at any timestamp
+    // only need a single read. On the other hand versions are never pruned,
+    // so the list, and with it every read and write, grows with each write to
+    // the key. Only use these on keys that are never written to directly.
+    //
+    // The value as of ts, written by write_at with a timestamp at or before
+    // ts. None if the key didn't exist yet at ts.
+    pub async fn read_at(&self, key: &str, ts: u64) -> Result<Option<String>, GlomerError> {
+        let Some(versions) = self.read_opt(key).await? else {
+            return Ok(None);
+        };
+        Ok(parse_versions(&versions)?
+            .into_iter()
+            .take_while(|&(version, _)| version <= ts)
+            .last()
+            .map(|(_, value)| value))
+    }
+
+    // Add a version of key as of ts, replacing any version with the same ts.
+    // Versions are added with a compare and swap loop, so concurrent writers
+    // never lose each other's versions.
+    pub async fn write_at(&self, key: &str, ts: u64, value: &str) -> Result<(), GlomerError> {
+        let mut current = self.read_opt(key).await?;
+        loop {
+            let mut versions = match &current {
+                Some(current) => parse_versions(current)?,
+                None => Vec::new(),
+            };
+            match versions.binary_search_by_key(&ts, |&(version, _)| version) {
+                Ok(i) => versions[i].1 = value.to_string(),
+                Err(i) => versions.insert(i, (ts, value.to_string())),
+            }
+            let new = serde_json::to_string(&versions)?;
+            // A missing key is created, whatever from is
+            let from = current.as_deref().unwrap_or_default();
+            current = match self.compare_and_swap(key, from, &new, true).await {
+                Ok(()) => return Ok(()),
+                Err(GlomerError::CasConflict { current }) => Some(current),
+                Err(GlomerError::Maelstrom(MaelstromError {
+                    code: ErrorCode::PreconditionFailed,
+                    ..
+                })) => self.read_opt(key).await?,
+                Err(e) => return Err(e),
+            };
+        }
+    }
+
+    // Same as read_int, with default for a key that doesn't exist
+    pub async fn read_int_or(&self, key: &str, default: i64) -> Result<i64, GlomerError> {
+        match self.read_opt(key).await? {
+            Some(value) => {
+                serde_json::from_str(&value).map_err(|e| GlomerError::Parse(e.to_string()))
+            }
+            None => Ok(default),
+        }
+    }
+}
+
+// Versions stored by write_at, see KvClient::read_at
+fn parse_versions(versions: &str) -> Result<Vec<(u64, String)>, GlomerError> {
+    serde_json::from_str(versions).map_err(|e| GlomerError::Parse(e.to_string()))
+}
+
+// Maelstrom's kv services report a failed compare and swap as
+// "current value <value> is not <from>", with values printed as Clojure data,
+// so the strings we store show up quoted
+fn parse_current_value(text: &str) -> Option<String> {
+    let (current, _) = text
+        .strip_prefix("current value ")?
+        .rsplit_once(" is not ")?;
+    if current.starts_with('"') {
+        serde_json::from_str(current).ok()
+    } else {
+        Some(current.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::KvClient;
+    use crate::{
+        error::{GlomerError, MaelstromError},
+        node::testing::TestHarness,
+        Handler, MaelstromMessage, Node,
+    };
+
+    // Runs the requested operation and replies with how it failed
+    struct Client(Node, KvClient);
+
+    impl Handler<Value> for Client {
+        async fn handle(&self, msg: &MaelstromMessage<Value>) -> Result<(), MaelstromError> {
+            let res = match msg.body.payload["type"].as_str() {
+                Some("read") => self.1.read("k").await.map(|_| ()),
+                Some("write") => self.1.write("k", "v").await,
+                _ => self.1.compare_and_swap("k", "v", "w", false).await,
+            };
+            let failure = match res {
+                Ok(()) => json!("none"),
+                Err(GlomerError::Maelstrom(err)) => json!({"code": err.code.raw()}),
+                Err(GlomerError::UnexpectedResponse { .. }) => json!("unexpected"),
+                Err(err) => json!(err.to_string()),
+            };
+            self.0
+                .reply(msg, json!({"type": "result", "failure": failure}))?;
+            Ok(())
+        }
+    }
+
+    fn message(src: &str, body: &Value) -> String {
+        json!({"src": src, "dest": "n1", "body": body}).to_string()
+    }
+
+    #[tokio::test]
+    async fn service_errors_are_passed_on() {
+        let unavailable = |in_reply_to: u64| {
+            message(
+                "lin-kv",
+                &json!({"type": "error", "in_reply_to": in_reply_to, "code": 11, "text": "busy"}),
+            )
+        };
+        // Every request sends one RPC and one reply, so the RPCs are odd msg_ids
+        let output = TestHarness::new("n1", vec!["n1".into()])
+            .input(message("c1", &json!({"type": "read", "msg_id": 1})))
+            .input(unavailable(1))
+            .input(message("c1", &json!({"type": "write", "msg_id": 2})))
+            .input(unavailable(3))
+            .input(message("c1", &json!({"type": "cas", "msg_id": 3})))
+            .input(unavailable(5))
+            .input(message("c1", &json!({"type": "cas", "msg_id": 4})))
+            .input(message(
+                "lin-kv",
+                &json!({"type": "write_ok", "in_reply_to": 7}),
+            ))
+            .run(|node| Client(node.clone(), KvClient::lin(node)))
+            .await
+            .unwrap();
+        let failures: Vec<Value> = output
+            .iter()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .filter(|msg| msg["dest"] == "c1")
+            .map(|msg| msg["body"]["failure"].clone())
+            .collect();
+        assert_eq!(
+            failures,
+            vec![
+                json!({"code": 11}),
+                json!({"code": 11}),
+                json!({"code": 11}),
+                json!("unexpected"),
+            ]
+        );
+    }
+}
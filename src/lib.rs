@@ -1,9 +1,13 @@
-#![feature(box_into_inner)]
-
+pub mod clock;
 pub mod error;
+pub mod kv_client;
+pub mod lin_tso_client;
+mod macros;
 pub mod message;
+pub mod metrics;
 pub mod node;
-pub mod seq_kv_client;
+pub mod retry;
+pub mod vector_clock;
 
-pub use message::MaelstromMessage;
-pub use node::{node_id, parse_node_id, Handler, Node};
+pub use message::{MaelstromMessage, NodeId, RawMessage};
+pub use node::{node_id, parse_node_id, Handler, InitInfo, Node, NodeBuilder, RateLimitMode};
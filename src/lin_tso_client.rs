@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{ErrorCode, GlomerError, MaelstromError},
+    node::Node,
+    retry::RetryPolicy,
+};
+
+const SERVICE: &str = "lin-tso";
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RequestPayload {
+    Ts,
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ResponsePayload {
+    TsOk { ts: u64 },
+}
+
+// Client for the lin-tso timestamp oracle, which hands out strictly
+// increasing timestamps across the whole cluster
+#[derive(Clone)]
+pub struct LinTsoClient {
+    node: Node,
+    retry: Option<RetryPolicy>,
+}
+
+impl LinTsoClient {
+    #[must_use]
+    pub const fn new(node: Node) -> Self {
+        Self { node, retry: None }
+    }
+
+    // See KvClient::with_retry, retrying a timestamp request is always safe
+    #[must_use]
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    // A timestamp greater than any handed out before. Fails with Unsupported
+    // if the workload doesn't run lin-tso.
+    pub async fn timestamp(&self) -> Result<u64, GlomerError> {
+        let response = match &self.retry {
+            Some(policy) => {
+                self.node
+                    .send_rpc_retry(SERVICE, RequestPayload::Ts, policy)
+                    .await
+            }
+            None => self.node.send_rpc(SERVICE, RequestPayload::Ts, None).await,
+        };
+        match response {
+            Ok(ResponsePayload::TsOk { ts }) => Ok(ts),
+            Err(GlomerError::Maelstrom(MaelstromError {
+                code: ErrorCode::NotSupported,
+                ..
+            })) => Err(GlomerError::Unsupported(format!(
+                "{SERVICE} is not available in this workload"
+            ))),
+            Err(e) => Err(e),
+        }
+    }
+}
@@ -0,0 +1,37 @@
+// Declares a message payload enum with the attributes every payload shares: the
+// variant name in snake_case becomes the message's type field. The word before
+// enum picks the serde derives, since most payloads only go one way:
+// - request: only received, derives Deserialize
+// - response: only sent, derives Serialize
+// - message: both, e.g. messages between nodes
+//
+// maelstrom_payload! {
+//     request enum RequestPayload {
+//         Echo { echo: String },
+//     }
+// }
+#[macro_export]
+macro_rules! maelstrom_payload {
+    ($(#[$meta:meta])* $vis:vis request enum $name:ident $($body:tt)*) => {
+        $crate::maelstrom_payload! {
+            @payload (::serde::Deserialize) $(#[$meta])* $vis enum $name $($body)*
+        }
+    };
+    ($(#[$meta:meta])* $vis:vis response enum $name:ident $($body:tt)*) => {
+        $crate::maelstrom_payload! {
+            @payload (::serde::Serialize) $(#[$meta])* $vis enum $name $($body)*
+        }
+    };
+    ($(#[$meta:meta])* $vis:vis message enum $name:ident $($body:tt)*) => {
+        $crate::maelstrom_payload! {
+            @payload (::serde::Serialize, ::serde::Deserialize)
+            $(#[$meta])* $vis enum $name $($body)*
+        }
+    };
+    (@payload ($($derive:path),+) $(#[$meta:meta])* $vis:vis enum $name:ident $($body:tt)*) => {
+        $(#[$meta])*
+        #[derive(Clone, Debug, $($derive),+)]
+        #[serde(tag = "type", rename_all = "snake_case")]
+        $vis enum $name $($body)*
+    };
+}
@@ -1,4 +1,9 @@
-use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::GlomerError;
 
 #[allow(clippy::module_name_repetitions)]
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -8,12 +13,90 @@ pub struct MaelstromMessage<P> {
     pub body: Body<P>,
 }
 
+impl<P> MaelstromMessage<P> {
+    // Whether the message was sent by a Maelstrom client rather than another
+    // node or one of Maelstrom's services
+    #[must_use]
+    pub fn is_from_client(&self) -> bool {
+        matches!(self.src.parse(), Ok(NodeId::Client(_)))
+    }
+}
+
+// Id of a node or a client, serialized as Maelstrom's n1 or c1. Services like
+// lin-kv have ids that are neither, so don't parse as a NodeId.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub enum NodeId {
+    Node(u32),
+    Client(u32),
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Node(id) => write!(f, "n{id}"),
+            Self::Client(id) => write!(f, "c{id}"),
+        }
+    }
+}
+
+impl FromStr for NodeId {
+    type Err = GlomerError;
+
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        let parsed = match id.split_at_checked(1) {
+            Some(("n", number)) => number.parse().map(Self::Node),
+            Some(("c", number)) => number.parse().map(Self::Client),
+            _ => return Err(GlomerError::Parse(format!("Invalid node id {id}"))),
+        };
+        parsed.map_err(|_| GlomerError::Parse(format!("Invalid node id {id}")))
+    }
+}
+
+impl TryFrom<String> for NodeId {
+    type Error = GlomerError;
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        id.parse()
+    }
+}
+
+impl From<NodeId> for String {
+    fn from(id: NodeId) -> Self {
+        id.to_string()
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Body<P> {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub msg_id: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub in_reply_to: Option<u64>,
+    // Sender's Lamport clock, only set by nodes with the clock enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lamport: Option<u64>,
     #[serde(flatten)]
     pub payload: P,
 }
+
+// A message whose payload hasn't been parsed into a concrete type
+pub type RawMessage = MaelstromMessage<Value>;
+
+impl RawMessage {
+    // The payload's type field, None if it is missing or not a string
+    #[must_use]
+    pub fn payload_type(&self) -> Option<&str> {
+        self.body.payload.get("type").and_then(Value::as_str)
+    }
+
+    // Parse the payload into a concrete type, e.g. once payload_type has
+    // determined which one it should be
+    pub fn parse_payload<T>(&self) -> Result<T, GlomerError>
+    where
+        T: DeserializeOwned,
+    {
+        Ok(T::deserialize(&self.body.payload)?)
+    }
+}
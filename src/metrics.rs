@@ -0,0 +1,70 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use tokio::time::Duration;
+
+// Upper bounds of the latency histogram buckets, anything slower ends up in
+// one final overflow bucket
+pub const LATENCY_BUCKETS: [Duration; 5] = [
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+
+// Handler statistics for a single message type
+#[derive(Clone, Debug, Default)]
+pub struct MessageStats {
+    pub count: u64,
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+    // Number of messages per bucket of LATENCY_BUCKETS, plus the overflow bucket
+    pub latency_histogram: [u64; LATENCY_BUCKETS.len() + 1],
+}
+
+impl MessageStats {
+    #[must_use]
+    pub fn mean_latency(&self) -> Duration {
+        match u32::try_from(self.count) {
+            Ok(0) => Duration::ZERO,
+            Ok(count) => self.total_latency / count,
+            Err(_) => self.total_latency.div_f64(self.count as f64),
+        }
+    }
+
+    fn record(&mut self, latency: Duration) {
+        self.count += 1;
+        self.total_latency += latency;
+        self.max_latency = self.max_latency.max(latency);
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| latency <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency_histogram[bucket] += 1;
+    }
+}
+
+// Collects MessageStats keyed by message type
+#[derive(Debug, Default)]
+pub struct Metrics {
+    stats: Mutex<BTreeMap<String, MessageStats>>,
+}
+
+impl Metrics {
+    pub fn record(&self, msg_type: &str, latency: Duration) {
+        let mut guard = self.stats.lock().unwrap();
+        if let Some(stats) = guard.get_mut(msg_type) {
+            stats.record(latency);
+        } else {
+            guard
+                .entry(msg_type.to_string())
+                .or_default()
+                .record(latency);
+        }
+    }
+
+    #[must_use]
+    pub fn snapshot(&self) -> BTreeMap<String, MessageStats> {
+        self.stats.lock().unwrap().clone()
+    }
+}
@@ -1,38 +1,83 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    env,
     fmt::Debug,
+    fs::{File, OpenOptions},
     future::Future,
-    io::{stdin, BufRead as _},
+    io::Write as _,
+    panic,
+    path::PathBuf,
     sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, RwLock,
     },
+    time::SystemTime,
 };
 
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
 use tokio::{
-    sync::oneshot,
-    time::{timeout, Duration},
+    io::{
+        AsyncBufRead, AsyncBufReadExt as _, AsyncWrite, AsyncWriteExt as _, BufReader, BufWriter,
+    },
+    signal::unix::{signal, SignalKind},
+    sync::{self, mpsc, oneshot, Semaphore},
+    task::JoinSet,
+    time::{timeout, Duration, Instant},
 };
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::Instrument as _;
+use tracing_subscriber::EnvFilter;
 
 use crate::{
-    error::{error_type, GlomerError, MaelstromError},
-    message::{Body, MaelstromMessage},
+    clock::Clock,
+    error::{ErrorCode, GlomerError, MaelstromError},
+    message::{Body, MaelstromMessage, NodeId, RawMessage},
+    metrics::{MessageStats, Metrics},
+    retry::RetryPolicy,
 };
 
+pub mod testing;
+
+// Outbound messages are buffered by the writer task, which flushes whenever it
+// has drained its queue (or sooner, whenever the buffer fills up). A lone
+// message is written out immediately, while a burst of messages from many
+// handlers gets batched into a few large writes.
+const OUTPUT_BUFFER_SIZE: usize = 64 * 1024;
+
+// Maximum number of messages being handled at the same time, see
+// NodeBuilder::max_concurrency
+pub const DEFAULT_MAX_CONCURRENCY: usize = 1024;
+
+// Timeout for RPCs sent without one, see NodeBuilder::default_rpc_timeout
+pub const DEFAULT_RPC_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Timeout that makes an RPC wait for its response for as long as it takes
+pub const NO_TIMEOUT: Duration = Duration::MAX;
+
+// Number of requests remembered for dedup, see NodeBuilder::dedup_capacity
+pub const DEFAULT_DEDUP_CAPACITY: usize = 10_000;
+
+// Number of RPCs awaiting a response at which a warning is logged, since that
+// many usually means peers have stopped responding
+const PENDING_RPCS_WARNING: usize = 1000;
+
+// Environment variable with the path of the outbound message log, see
+// NodeBuilder::wal
+pub const WAL_ENV_VAR: &str = "GLOMER_WAL";
+
 #[allow(clippy::module_name_repetitions)]
 #[must_use]
 pub fn node_id(id: u32) -> String {
-    format!("n{id}")
+    NodeId::Node(id).to_string()
 }
 
+// Number of a node id, clients' ids are rejected
 pub fn parse_node_id(id: &str) -> Result<u32, GlomerError> {
-    id.strip_prefix("n")
-        .ok_or_else(|| GlomerError::Parse("Invalid node id".into()))?
-        .parse()
-        .map_err(|_| GlomerError::Parse("Invalid node id".into()))
+    match id.parse()? {
+        NodeId::Node(id) => Ok(id),
+        NodeId::Client(_) => Err(GlomerError::Parse(format!("{id} is a client, not a node"))),
+    }
 }
 
 // Handler trait - user needs to impl these methods to handle messages
@@ -44,15 +89,117 @@ pub trait Handler<P> {
     ) -> impl Future<Output = Result<(), MaelstromError>> + Send
     where
         P: DeserializeOwned;
+
+    // Same as handle, along with the line the message was parsed from, e.g.
+    // to log exactly what was received. The line is only borrowed for the
+    // call. Defaults to handle, ignoring the line.
+    fn handle_line(
+        &self,
+        msg: &MaelstromMessage<P>,
+        line: &str,
+    ) -> impl Future<Output = Result<(), MaelstromError>> + Send
+    where
+        P: DeserializeOwned,
+    {
+        let _ = line;
+        self.handle(msg)
+    }
+
+    // Fallback for messages whose payload doesn't deserialize into P, e.g. a
+    // message type missing from the handler's enum. Receives the raw JSON payload.
+    // Defaults to replying NotSupported.
+    fn handle_unknown(
+        &self,
+        msg: &RawMessage,
+    ) -> impl Future<Output = Result<(), MaelstromError>> + Send {
+        let text = match msg.payload_type() {
+            Some(msg_type) => format!("Unsupported message type: {msg_type}"),
+            None => "Message is missing a type".to_string(),
+        };
+        async move { Err(MaelstromError::not_supported(text)) }
+    }
+
+    // Receives error messages that aren't a reply to one of our RPCs, e.g. a
+    // service reporting a failure asynchronously. These are never answered, so
+    // two nodes can't end up trading errors. Defaults to logging the error.
+    fn handle_error(&self, src: &str, err: &MaelstromError) -> impl Future<Output = ()> + Send {
+        eprintln!("ERROR: Received unsolicited error from {src}: {err}");
+        async {}
+    }
+
+    // Called once by run before it reads its first message, so no message is
+    // handled before on_init has returned, e.g. to set up per-peer state from
+    // node.node_ids. Tasks spawned before run may already be running though.
+    // Defaults to doing nothing.
+    fn on_init(&self, node: &Node) -> impl Future<Output = ()> + Send {
+        let _ = node;
+        async {}
+    }
+
+    // Answers debug_dump messages, which reply with the returned JSON object.
+    // Receives the node's own state, see Node::debug_state, which handlers can
+    // extend with theirs. Defaults to the node's state as is.
+    fn handle_debug(&self, node_state: Value) -> impl Future<Output = Value> + Send {
+        async { node_state }
+    }
+}
+
+// Lets a handler be shared with tasks outside of Node::run, e.g. a gossip
+// loop, by handing run an Arc of it instead of cloning the handler
+impl<P, H> Handler<P> for Arc<H>
+where
+    H: Handler<P>,
+{
+    fn handle(
+        &self,
+        msg: &MaelstromMessage<P>,
+    ) -> impl Future<Output = Result<(), MaelstromError>> + Send
+    where
+        P: DeserializeOwned,
+    {
+        (**self).handle(msg)
+    }
+
+    fn handle_line(
+        &self,
+        msg: &MaelstromMessage<P>,
+        line: &str,
+    ) -> impl Future<Output = Result<(), MaelstromError>> + Send
+    where
+        P: DeserializeOwned,
+    {
+        (**self).handle_line(msg, line)
+    }
+
+    fn handle_unknown(
+        &self,
+        msg: &RawMessage,
+    ) -> impl Future<Output = Result<(), MaelstromError>> + Send {
+        (**self).handle_unknown(msg)
+    }
+
+    fn handle_error(&self, src: &str, err: &MaelstromError) -> impl Future<Output = ()> + Send {
+        (**self).handle_error(src, err)
+    }
+
+    fn on_init(&self, node: &Node) -> impl Future<Output = ()> + Send {
+        (**self).on_init(node)
+    }
+
+    fn handle_debug(&self, node_state: Value) -> impl Future<Output = Value> + Send {
+        (**self).handle_debug(node_state)
+    }
 }
 
-// Init messages - internally used to initialize the node
+// Init message - used to initialize the node, and handed to callers of
+// init_with_info who want to build their handler from it
 #[derive(Deserialize, Clone, Debug)]
 #[serde(tag = "type", rename = "init")]
-struct Init {
-    node_id: String,
-    #[allow(unused)]
-    node_ids: Vec<String>,
+pub struct InitInfo {
+    // Our own id
+    pub node_id: String,
+    // All nodes in the cluster, including ourselves
+    pub node_ids: Vec<String>,
 }
 
 #[derive(Serialize, Clone, Debug)]
@@ -63,194 +210,1510 @@ struct InitOk {}
 pub struct Node {
     // Out NodeId
     pub id: u32,
+    // Same as node_id(id), formatted once at init
+    id_str: Arc<str>,
+    // All nodes in the cluster, including ourselves
+    pub node_ids: Arc<Vec<String>>,
     // Monotonically increasing message id
     pub next_msg_id: Arc<AtomicU64>,
     pub cancellation_token: CancellationToken,
     // Mapping from msg_id to channel on which to send response
-    pub(super) response_map: Arc<Mutex<BTreeMap<u64, oneshot::Sender<String>>>>,
+    pub(super) response_map: Arc<ResponseMap>,
+    // Shared with run() so that nothing buffered after the init line is lost
+    input: Arc<sync::Mutex<Input>>,
+    // Queue of serialized messages for the writer task, which owns stdout
+    output: mpsc::UnboundedSender<Output>,
+    // Per message type handler statistics, only collected if enabled
+    metrics: Option<Arc<Metrics>>,
+    // Limits the number of concurrently running handlers
+    handler_permits: Arc<Semaphore>,
+    // Recently handled requests, only tracked if enabled
+    dedup: Option<Arc<Mutex<DedupCache>>>,
+    // Used by send_rpc when no timeout is given
+    default_rpc_timeout: Duration,
+    // Lamport clock, only maintained if enabled
+    lamport: Option<Arc<AtomicU64>>,
+    // Reject requests without a string type before dispatching them
+    validate_type: bool,
+    // Lines longer than this many bytes are rejected without being parsed
+    max_line_length: Option<usize>,
+    time_source: Clock,
+    // Tasks started with spawn_periodic, which run waits for on shutdown
+    background: TaskTracker,
+    // Nodes that RPCs fail for without being sent, see set_unreachable
+    unreachable: Arc<RwLock<BTreeSet<String>>>,
+    // Log of every message sent, only written if enabled
+    wal: Option<Arc<Mutex<File>>>,
 }
 
-impl Node {
-    pub fn init() -> Result<Self, GlomerError> {
+// Configuration for a Node, applied once the init message has been read.
+// Node::init() is the same as NodeBuilder::default().init().
+#[derive(Debug, Clone)]
+pub struct NodeBuilder {
+    max_concurrency: usize,
+    flush_interval: Duration,
+    default_rpc_timeout: Duration,
+    dedup: bool,
+    dedup_capacity: usize,
+    metrics: bool,
+    lamport: bool,
+    rate_limit: Option<(u32, RateLimitMode)>,
+    validate_type: bool,
+    max_line_length: Option<usize>,
+    time_source: Clock,
+    unreachable: Arc<RwLock<BTreeSet<String>>>,
+    wal: Option<PathBuf>,
+}
+
+// What the writer does with a message sent while over the rate limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitMode {
+    // Hold it back until the limit allows it, delaying everything queued
+    // after it too. Senders never block, the output queue grows instead.
+    Block,
+    // Discard it, like the network would drop it
+    Drop,
+}
+
+impl Default for NodeBuilder {
+    fn default() -> Self {
+        Self {
+            max_concurrency: DEFAULT_MAX_CONCURRENCY,
+            flush_interval: Duration::ZERO,
+            default_rpc_timeout: DEFAULT_RPC_TIMEOUT,
+            dedup: false,
+            dedup_capacity: DEFAULT_DEDUP_CAPACITY,
+            metrics: false,
+            lamport: false,
+            rate_limit: None,
+            validate_type: false,
+            max_line_length: None,
+            time_source: Clock::System,
+            unreachable: Arc::default(),
+            wal: env::var_os(WAL_ENV_VAR).map(PathBuf::from),
+        }
+    }
+}
+
+impl NodeBuilder {
+    // Handlers beyond the limit wait for a running one to finish before they
+    // start, and no more input is read in the meantime, so a burst of
+    // requests pushes back on the sender instead of queueing up in memory.
+    // Responses to our own RPCs don't count towards the limit. They can
+    // still be stuck behind a request that is waiting for a permit though,
+    // until the RPCs holding the permits time out, so keep the limit well
+    // above the number of handlers that wait on RPCs at once. Defaults to
+    // DEFAULT_MAX_CONCURRENCY.
+    #[must_use]
+    pub const fn max_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.max_concurrency = max_concurrency;
+        self
+    }
+
+    // How long output may wait in the buffer once nothing else is queued, so
+    // that messages sent shortly after each other share a write. Defaults to
+    // zero, writing out messages as soon as the queue is drained.
+    #[must_use]
+    pub const fn flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    // Timeout for RPCs sent without one, so a peer that never replies can't
+    // hang a handler. Pass NO_TIMEOUT to wait forever instead. Defaults to
+    // DEFAULT_RPC_TIMEOUT.
+    #[must_use]
+    pub const fn default_rpc_timeout(mut self, timeout_duration: Duration) -> Self {
+        self.default_rpc_timeout = timeout_duration;
+        self
+    }
+
+    // Only handle the first delivery of each request, identified by its src and
+    // msg_id. Duplicates are answered with the reply to the original request,
+    // or dropped if that is still being handled. Disabled by default.
+    #[must_use]
+    pub const fn enable_dedup(mut self, enable: bool) -> Self {
+        self.dedup = enable;
+        self
+    }
+
+    // Number of requests remembered for dedup, along with their reply. The
+    // oldest request is forgotten first, so memory is bounded by capacity
+    // times the size of a reply. Defaults to DEFAULT_DEDUP_CAPACITY.
+    #[must_use]
+    pub const fn dedup_capacity(mut self, capacity: usize) -> Self {
+        self.dedup_capacity = capacity;
+        self
+    }
+
+    // Collect handler statistics per message type, which are written to stderr
+    // on shutdown. Disabled by default.
+    #[must_use]
+    pub const fn enable_metrics(mut self, enable: bool) -> Self {
+        self.metrics = enable;
+        self
+    }
+
+    // Maintain a Lamport clock, which ticks on every message sent and is
+    // stamped on it as lamport, and advances past the lamport field of every
+    // message received. Disabled by default.
+    #[must_use]
+    pub const fn enable_lamport_clock(mut self, enable: bool) -> Self {
+        self.lamport = enable;
+        self
+    }
+
+    // Limit outbound messages to messages_per_second, allowing bursts of up to
+    // one second's worth. Responses to clients count towards the limit just
+    // like gossip, so use Drop with care. Zero disables the limit, which is
+    // the default.
+    #[must_use]
+    pub const fn rate_limit(mut self, messages_per_second: u32, mode: RateLimitMode) -> Self {
+        self.rate_limit = if messages_per_second == 0 {
+            None
+        } else {
+            Some((messages_per_second, mode))
+        };
+        self
+    }
+
+    // Answer requests whose body has no string type field with
+    // MalformedRequest, instead of handing them to Handler::handle_unknown.
+    // Disabled by default.
+    #[must_use]
+    pub const fn validate_type(mut self, enable: bool) -> Self {
+        self.validate_type = enable;
+        self
+    }
+
+    // Answer messages longer than max_bytes with MalformedRequest, without
+    // parsing more than the sender and msg_id, e.g. to catch gossip that keeps
+    // growing. Zero disables the limit, which is the default.
+    #[must_use]
+    pub const fn max_line_length(mut self, max_bytes: usize) -> Self {
+        self.max_line_length = if max_bytes == 0 {
+            None
+        } else {
+            Some(max_bytes)
+        };
+        self
+    }
+
+    // Where Node::now gets the time from, e.g. Clock::mock in tests. Defaults
+    // to the system clock.
+    #[must_use]
+    pub fn time_source(mut self, time_source: Clock) -> Self {
+        self.time_source = time_source;
+        self
+    }
+
+    // Append every message sent to the file at path as well, one per line,
+    // e.g. to compare against Maelstrom's history after a failed run. Nodes
+    // sharing a path all append to the same file. Defaults to the path in
+    // WAL_ENV_VAR if it is set, otherwise nothing is logged.
+    #[must_use]
+    pub fn wal(mut self, path: Option<PathBuf>) -> Self {
+        self.wal = path;
+        self
+    }
+
+    // Lets the simulator partition a node before it has been initialized
+    pub(crate) fn unreachable(mut self, unreachable: Arc<RwLock<BTreeSet<String>>>) -> Self {
+        self.unreachable = unreachable;
+        self
+    }
+
+    pub async fn init(self) -> Result<Node, GlomerError> {
+        Ok(self.init_with_info().await?.0)
+    }
+
+    // Same as init, also returning the init message
+    pub async fn init_with_info(self) -> Result<(Node, InitInfo), GlomerError> {
+        self.init_node(BufReader::new(tokio::io::stdin()), tokio::io::stdout())
+            .await
+    }
+
+    // Same as init, but reading messages from input and writing them to output
+    // instead of stdin and stdout, e.g. to run several nodes in one process.
+    // Input has to start with the init message, just like stdin would.
+    pub async fn init_with_io<I, O>(self, input: I, output: O) -> Result<Node, GlomerError>
+    where
+        I: AsyncBufRead + Send + Unpin + 'static,
+        O: AsyncWrite + Send + Unpin + 'static,
+    {
+        Ok(self.init_node(input, output).await?.0)
+    }
+
+    async fn init_node<I, O>(self, mut input: I, output: O) -> Result<(Node, InitInfo), GlomerError>
+    where
+        I: AsyncBufRead + Send + Unpin + 'static,
+        O: AsyncWrite + Send + Unpin + 'static,
+    {
         let mut buffer = String::new();
-        stdin().read_line(&mut buffer)?;
-        let init_msg: MaelstromMessage<Init> =
-            serde_json::from_str::<MaelstromMessage<Init>>(&buffer)?;
-        let node = Self {
+        input.read_line(&mut buffer).await?;
+        let init_msg: MaelstromMessage<InitInfo> =
+            serde_json::from_str::<MaelstromMessage<InitInfo>>(&buffer)?;
+        let wal = match &self.wal {
+            Some(path) => Some(Arc::new(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            ))),
+            None => None,
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        let rate_limiter = self
+            .rate_limit
+            .map(|(per_second, mode)| RateLimiter::new(per_second, mode));
+        tokio::spawn(write_output(output, rx, self.flush_interval, rate_limiter));
+        let node = Node {
             id: parse_node_id(&init_msg.body.payload.node_id)?,
+            id_str: init_msg.body.payload.node_id.as_str().into(),
+            node_ids: Arc::new(init_msg.body.payload.node_ids.clone()),
             next_msg_id: Arc::new(0.into()),
             cancellation_token: CancellationToken::new(),
-            response_map: Arc::new(Mutex::new(BTreeMap::new())),
+            response_map: Arc::new(ResponseMap::default()),
+            input: Arc::new(sync::Mutex::new(Input(Box::new(input)))),
+            output: tx,
+            metrics: self.metrics.then(|| Arc::new(Metrics::default())),
+            handler_permits: Arc::new(Semaphore::new(self.max_concurrency)),
+            dedup: self
+                .dedup
+                .then(|| Arc::new(Mutex::new(DedupCache::new(self.dedup_capacity)))),
+            default_rpc_timeout: self.default_rpc_timeout,
+            lamport: self.lamport.then(|| Arc::new(AtomicU64::new(0))),
+            validate_type: self.validate_type,
+            max_line_length: self.max_line_length,
+            time_source: self.time_source,
+            background: TaskTracker::new(),
+            unreachable: self.unreachable,
+            wal,
         };
 
-        // Let maelstrom know that we are initialized
-        node.reply(&init_msg, InitOk {});
+        // Let maelstrom know that we are initialized, without waiting for
+        // the flush interval
+        node.reply(&init_msg, InitOk {})?;
+        node.flush().await?;
+
+        Ok((node, init_msg.body.payload))
+    }
+}
+
+impl Node {
+    // Initialize with the default configuration, see NodeBuilder
+    pub async fn init() -> Result<Self, GlomerError> {
+        NodeBuilder::default().init().await
+    }
+
+    // Initialize with the default configuration, also returning the init
+    // message for building the handler from it
+    pub async fn init_with_info() -> Result<(Self, InitInfo), GlomerError> {
+        NodeBuilder::default().init_with_info().await
+    }
+
+    // Initialize with the default configuration, see NodeBuilder::init_with_io
+    pub async fn init_with_io<I, O>(input: I, output: O) -> Result<Self, GlomerError>
+    where
+        I: AsyncBufRead + Send + Unpin + 'static,
+        O: AsyncWrite + Send + Unpin + 'static,
+    {
+        NodeBuilder::default().init_with_io(input, output).await
+    }
+
+    #[must_use]
+    pub fn builder() -> NodeBuilder {
+        NodeBuilder::default()
+    }
+
+    // Our own id as used in src and dest, e.g. "n1"
+    #[must_use]
+    pub fn id_str(&self) -> &str {
+        &self.id_str
+    }
+
+    // Current value of the Lamport clock, None unless enabled with
+    // NodeBuilder::enable_lamport_clock
+    #[must_use]
+    pub fn clock(&self) -> Option<u64> {
+        self.lamport
+            .as_ref()
+            .map(|clock| clock.load(Ordering::SeqCst))
+    }
+
+    // Advance the Lamport clock for a local event, returning the new value.
+    // Sending a message already ticks the clock.
+    pub fn tick(&self) -> Option<u64> {
+        self.lamport
+            .as_ref()
+            .map(|clock| clock.fetch_add(1, Ordering::SeqCst) + 1)
+    }
+
+    // Empty unless metrics have been enabled with NodeBuilder::enable_metrics
+    #[must_use]
+    pub fn metrics_snapshot(&self) -> BTreeMap<String, MessageStats> {
+        self.metrics
+            .as_ref()
+            .map(|metrics| metrics.snapshot())
+            .unwrap_or_default()
+    }
+
+    // Snapshot of the node's internals for debug_dump, see
+    // Handler::handle_debug. Latencies are in microseconds.
+    #[must_use]
+    pub fn debug_state(&self) -> Value {
+        let metrics: serde_json::Map<_, _> = self
+            .metrics_snapshot()
+            .into_iter()
+            .map(|(msg_type, stats)| {
+                let stats = serde_json::json!({
+                    "count": stats.count,
+                    "mean_latency": stats.mean_latency().as_micros(),
+                    "max_latency": stats.max_latency.as_micros(),
+                    "latency_histogram": stats.latency_histogram,
+                });
+                (msg_type, stats)
+            })
+            .collect();
+        serde_json::json!({
+            "node_id": self.id_str(),
+            "node_ids": *self.node_ids,
+            "pending_rpcs": self.pending_rpcs(),
+            "metrics": metrics,
+        })
+    }
+
+    // Current wall clock time, from the clock the node was built with. Use
+    // this rather than SystemTime::now for anything tests should control.
+    #[must_use]
+    pub fn now(&self) -> SystemTime {
+        self.time_source.now()
+    }
+
+    // Make RPCs to these nodes fail with NodeNotFound straight away, without
+    // sending anything, to model a partition in tests. Replaces the previous
+    // set, everything is reachable by default.
+    pub fn set_unreachable<I, S>(&self, nodes: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        *self.unreachable.write().unwrap() = nodes.into_iter().map(Into::into).collect();
+    }
+
+    // Number of RPCs sent that are still waiting for their response
+    #[must_use]
+    pub fn pending_rpcs(&self) -> usize {
+        self.response_map.len()
+    }
 
-        Ok(node)
+    // Log to stderr (which Maelstrom ignores), filtered according to RUST_LOG.
+    // Does nothing if a global subscriber has already been set.
+    pub fn init_tracing() {
+        let _ = tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::from_default_env())
+            .with_writer(std::io::stderr)
+            .try_init();
     }
 
     // Main process loop - initializes node then reads messages from stdin in a loop
     // Will automatically respond to requests with formatted error on handle() error
+    // The handler is shared between the tasks handling each message, so it
+    // never needs to be Clone. Pass an Arc of it to keep using it elsewhere.
+    // Once input ends (or on SIGTERM) every handler already running is
+    // drained before returning. RPCs they are still waiting on fail with
+    // GlomerError::Cancelled rather than Abort, and aren't answered with an
+    // error, since the client did nothing wrong.
     pub async fn run<P, H>(&self, handler: H) -> Result<(), GlomerError>
+    where
+        P: DeserializeOwned + Debug + Send + Sync + 'static,
+        H: Handler<P> + Send + Sync + 'static,
+    {
+        self.run_until(handler, None).await
+    }
+
+    // Same as run, but shuts down once duration has passed even if input is
+    // still open, e.g. to run a handler for a fixed window in a test
+    pub async fn run_for<P, H>(&self, handler: H, duration: Duration) -> Result<(), GlomerError>
+    where
+        P: DeserializeOwned + Debug + Send + Sync + 'static,
+        H: Handler<P> + Send + Sync + 'static,
+    {
+        self.run_until(handler, Instant::now().checked_add(duration))
+            .await
+    }
+
+    async fn run_until<P, H>(
+        &self,
+        handler: H,
+        deadline: Option<Instant>,
+    ) -> Result<(), GlomerError>
     where
         P: DeserializeOwned + Debug + Send + Sync + 'static,
         H: Handler<P> + Send + Sync + 'static,
     {
         let tracker = TaskTracker::new();
+        handler.on_init(self).await;
         // Initialize the user's handler, store in Arc to clone for each request
         let handler = Arc::new(handler);
-        for line in stdin().lock().lines() {
-            let line = line?;
-            // Deserialize message from input
+
+        // Maelstrom signals nodes when it tears them down, treat that the same
+        // as stdin closing
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let mut sigterm = signal(SignalKind::terminate())?;
+
+        let deadline = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(deadline);
+
+        let mut input = self.input.lock().await;
+        let mut lines = (&mut input.0).lines();
+        loop {
+            let line = tokio::select! {
+                line = lines.next_line() => line?,
+                _ = sigint.recv() => {
+                    eprintln!("INFO: Received SIGINT, shutting down");
+                    None
+                }
+                _ = sigterm.recv() => {
+                    eprintln!("INFO: Received SIGTERM, shutting down");
+                    None
+                }
+                () = &mut deadline => {
+                    eprintln!("INFO: Run duration elapsed, shutting down");
+                    None
+                }
+            };
+            let Some(line) = line else {
+                break;
+            };
+            // Skip blank lines between messages. CRLF line endings are
+            // already stripped by lines, and serde ignores trailing whitespace.
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            // Requests wait for a handler permit before they get a task, so
+            // reading stops while max_concurrency handlers are running.
+            // Responses to our RPCs skip the limit, the handlers holding the
+            // permits may be waiting on them.
+            let permit = if self.is_pending_response(&line) {
+                None
+            } else {
+                // The semaphore is never closed
+                let Ok(permit) = self.handler_permits.clone().acquire_owned().await else {
+                    break;
+                };
+                Some(permit)
+            };
 
             // Spawn new task to handle input so we can keep processing more messages
             let handler = handler.clone();
             let node = self.clone();
             tracker.spawn(async move {
-                // TODO I don't love this, is there a better way?
-                let in_reply_to: Option<u64> = serde_json::from_str::<Value>(&line)
-                    .unwrap()
-                    .get("body")
-                    .unwrap()
-                    .get("in_reply_to")
-                    .and_then(|v| serde_json::from_value(v.clone()).ok());
-
-                // If the received message is in response to an existing message,
-                // send the response to whichever task is waiting for it
-                if let Some(in_reply_to) = in_reply_to {
-                    let mut guard = node.response_map.lock().unwrap();
-                    if let Some(tx) = guard.remove(&in_reply_to) {
-                        if let Err(request_msg) = tx.send(line) {
-                            eprintln!(
-                                "INFO: Received response after operation timeout: {request_msg:?}"
-                            );
-                        }
-                    }
-                } else {
-                    // TODO custom deserialization to proper error
-                    // The problem with this is that if we fail to parse the message,
-                    // we don't know who to respond to with an error!
-                    let request_msg = serde_json::from_str::<MaelstromMessage<P>>(&line).unwrap();
-
-                    let res = tokio::select! {
-                        res = handler.handle(&request_msg) => res,
-                        () = node.cancellation_token.cancelled() => Ok(()),
-                    };
-
-                    // Serialize and send error message from handler
-                    if let Err(err) = res {
-                        let error_type = err.code;
-                        node.fire_and_forget(None, request_msg.body.msg_id, request_msg.src, &err);
-
-                        match error_type {
-                            error_type::CRASH | error_type::ABORT => {
-                                panic!("Unrecoverable error: {}", err.text)
-                            }
-                            _ => {}
-                        }
-                    }
-                }
+                node.process_line(&*handler, &line).await;
+                drop(permit);
             });
         }
 
-        // Graceful shutdown, wait for outstanding tasks to finish
+        // Graceful shutdown, in this order so nothing sent is lost:
+        // - cancel, which fails pending RPCs, wakes Node::sleep and stops periodic
+        //   tasks, but lets handlers run to completion
+        // - wait for the handlers, then the periodic tasks, to finish
+        // - flush, which only returns once everything they queued is written
         self.cancellation_token.cancel();
         tracker.close();
         tracker.wait().await;
+        self.background.close();
+        self.background.wait().await;
+        self.flush().await?;
+
+        for (msg_type, stats) in self.metrics_snapshot() {
+            eprintln!(
+                "INFO: {msg_type}: handled {} messages, mean latency {:?}, max latency {:?}, \
+                 histogram {:?}",
+                stats.count,
+                stats.mean_latency(),
+                stats.max_latency,
+                stats.latency_histogram
+            );
+        }
 
         Ok(())
     }
 
+    async fn process_line<P, H>(&self, handler: &H, line: &str)
+    where
+        P: DeserializeOwned + Debug + Send + Sync,
+        H: Handler<P> + Sync,
+    {
+        if let Some(max_bytes) = self.max_line_length.filter(|&max| line.len() > max) {
+            self.reject_long_line(line, max_bytes);
+            return;
+        }
+
+        // Parse the line once, leaving the payload untyped until we know
+        // whether it is a response to one of our RPCs or a new request
+        let msg = match serde_json::from_str::<MaelstromMessage<Value>>(line) {
+            Ok(msg) => msg,
+            Err(err) => {
+                // Without the envelope we don't know who to respond to
+                eprintln!("ERROR: Unable to parse message {line:?}: {err}");
+                return;
+            }
+        };
+
+        if let (Some(clock), Some(incoming)) = (&self.lamport, msg.body.lamport) {
+            let _ = clock.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |local| {
+                Some(local.max(incoming) + 1)
+            });
+        }
+
+        // If the received message is in response to one of our pending RPCs,
+        // send the response to whichever task is waiting for it. Anything
+        // else is handled as a request, even if it has an in_reply_to.
+        if let Some(in_reply_to) = msg.body.in_reply_to {
+            if let Some(tx) = self.response_map.remove(in_reply_to) {
+                if let Err(response_msg) = tx.send(msg) {
+                    eprintln!("INFO: Received response after operation timeout: {response_msg:?}");
+                }
+                return;
+            }
+        }
+
+        if self.validate_type && msg.payload_type().is_none() {
+            let err = MaelstromError::malformed_request("Message is missing a string type");
+            self.send_error(msg.body.msg_id, msg.src, &err);
+            return;
+        }
+
+        // Replying to an error with another error could bounce back and forth
+        // forever, so they go to a separate handler which can't reply
+        if msg.payload_type() == Some("error") {
+            match MaelstromError::deserialize(&msg.body.payload) {
+                Ok(err) => {
+                    tokio::select! {
+                        biased;
+                        () = handler.handle_error(&msg.src, &err) => {}
+                        () = self.cancellation_token.cancelled() => {}
+                    }
+                }
+                Err(err) => eprintln!("ERROR: Unable to parse error message {line:?}: {err}"),
+            }
+            return;
+        }
+
+        if msg.payload_type() == Some("debug_dump") {
+            let state = tokio::select! {
+                biased;
+                state = handler.handle_debug(self.debug_state()) => state,
+                () = self.cancellation_token.cancelled() => return,
+            };
+            let mut body = match state {
+                Value::Object(body) => body,
+                state => serde_json::Map::from_iter([("state".to_string(), state)]),
+            };
+            body.insert("type".into(), "debug_dump_ok".into());
+            if let Err(err) = self.reply(&msg, body) {
+                eprintln!("ERROR: Failed to reply to debug_dump: {err}");
+            }
+            return;
+        }
+
+        if let (Some(dedup), Some(msg_id)) = (&self.dedup, msg.body.msg_id) {
+            let duplicate = dedup.lock().unwrap().check(&msg.src, msg_id);
+            if let Some(reply) = duplicate {
+                eprintln!("INFO: Ignoring duplicate message {msg_id} from {}", msg.src);
+                if let Some(reply) = reply {
+                    if let Err(err) = self.queue_output(Output::Line(reply)) {
+                        eprintln!("ERROR: Failed to resend reply: {err}");
+                    }
+                }
+                return;
+            }
+        }
+
+        let msg_type = msg.payload_type();
+        let span = tracing::info_span!("handle", src = msg.src, msg_id = msg.body.msg_id, msg_type);
+
+        // The envelope parsed, so even if the payload doesn't we can still
+        // hand the raw message to the handler and reply to the sender.
+        // Handlers always run to completion, so every request read before
+        // shutdown is answered. RPCs and Node::sleep return early once the
+        // node is shutting down, so waiting on them doesn't hold it up.
+        let start = Instant::now();
+        let res = match P::deserialize(&msg.body.payload) {
+            Ok(payload) => {
+                let request_msg = MaelstromMessage {
+                    src: msg.src.clone(),
+                    dest: msg.dest.clone(),
+                    body: Body {
+                        msg_id: msg.body.msg_id,
+                        in_reply_to: msg.body.in_reply_to,
+                        lamport: msg.body.lamport,
+                        payload,
+                    },
+                };
+                handler
+                    .handle_line(&request_msg, line)
+                    .instrument(span)
+                    .await
+            }
+            // Most likely a response to an RPC that has already timed out,
+            // which must not be answered with an error like a request would
+            Err(_) if msg.body.in_reply_to.is_some() => {
+                eprintln!("INFO: Ignoring response to no pending RPC: {line}");
+                return;
+            }
+            Err(_) => handler.handle_unknown(&msg).instrument(span).await,
+        };
+
+        if let Some(metrics) = &self.metrics {
+            metrics.record(msg_type.unwrap_or("unknown"), start.elapsed());
+        }
+
+        // Serialize and send error message from handler. An abort while
+        // shutting down is most likely an RPC that was cancelled, which is
+        // neither the client's fault nor a reason to crash.
+        if let Err(err) = res {
+            let fatal = matches!(err.code, ErrorCode::Crash | ErrorCode::Abort);
+            if fatal && self.cancellation_token.is_cancelled() {
+                eprintln!("INFO: Handler failed while shutting down: {err}");
+                return;
+            }
+            self.send_error(msg.body.msg_id, msg.src, &err);
+            if fatal {
+                panic!("Unrecoverable error: {}", err.text)
+            }
+        }
+    }
+
+    // Whether the line answers an RPC still waiting for its response. Only
+    // skims the envelope, and not even that while no RPC is pending.
+    fn is_pending_response(&self, line: &str) -> bool {
+        if self.response_map.len() == 0 {
+            return false;
+        }
+        serde_json::from_str::<Envelope>(line)
+            .ok()
+            .and_then(|envelope| envelope.body.in_reply_to)
+            .is_some_and(|in_reply_to| self.response_map.contains(in_reply_to))
+    }
+
+    fn reject_long_line(&self, line: &str, max_bytes: usize) {
+        // Skims over the payload without building it
+        match serde_json::from_str::<Envelope>(line) {
+            Ok(envelope) => {
+                let err = MaelstromError::malformed_request(format!(
+                    "Message of {} bytes exceeds the limit of {max_bytes} bytes",
+                    line.len()
+                ));
+                self.send_error(envelope.body.msg_id, envelope.src, &err);
+            }
+            Err(err) => eprintln!(
+                "ERROR: Unable to parse message of {} bytes over the limit: {err}",
+                line.len()
+            ),
+        }
+    }
+
+    fn send_error(&self, in_reply_to: Option<u64>, dest: String, err: &MaelstromError) {
+        if let Err(send_err) = self.fire_and_forget(self.allocate_msg_id(), in_reply_to, dest, err)
+        {
+            eprintln!("ERROR: Failed to send error reply: {send_err}");
+        }
+    }
+
+    // Wait until every message queued so far has been written to stdout
+    async fn flush(&self) -> Result<(), GlomerError> {
+        let (tx, rx) = oneshot::channel();
+        self.queue_output(Output::Flushed(tx))?;
+        rx.await
+            .map_err(|_| GlomerError::Abort("Output writer has shut down".into()))
+    }
+
+    fn queue_output(&self, output: Output) -> Result<(), GlomerError> {
+        self.output
+            .send(output)
+            .map_err(|_| GlomerError::Abort("Output writer has shut down".into()))
+    }
+
+    // Every outbound message gets a unique msg_id, replies included. Responses
+    // are matched on in_reply_to alone, so this doesn't affect routing.
+    fn allocate_msg_id(&self) -> u64 {
+        self.next_msg_id.fetch_add(1, Ordering::Relaxed)
+    }
+
     fn fire_and_forget<P>(
         &self,
-        msg_id: Option<u64>,
+        msg_id: u64,
         in_reply_to: Option<u64>,
         dest: String,
         payload: &P,
-    ) where
+    ) -> Result<(), GlomerError>
+    where
         P: Serialize,
     {
+        self.fire_and_forget_raw(msg_id, in_reply_to, dest, &serde_json::to_string(payload)?)
+    }
+
+    // Every message sent ends up here with its payload already serialized,
+    // which is spliced into the envelope as is
+    fn fire_and_forget_raw(
+        &self,
+        msg_id: u64,
+        in_reply_to: Option<u64>,
+        dest: String,
+        body_json: &str,
+    ) -> Result<(), GlomerError> {
+        let fields = body_json
+            .trim()
+            .strip_prefix('{')
+            .and_then(|body| body.strip_suffix('}'))
+            .ok_or_else(|| GlomerError::Parse("Message body must be a JSON object".into()))?;
         let msg = MaelstromMessage {
-            src: node_id(self.id),
+            src: self.id_str.to_string(),
             dest,
             body: Body {
-                msg_id,
+                msg_id: Some(msg_id),
                 in_reply_to,
-                payload,
+                lamport: self.tick(),
+                payload: serde_json::Map::new(),
             },
         };
-        let msg = serde_json::to_string(&msg).unwrap();
-        println!("{msg}");
+        let mut line = serde_json::to_string(&msg)?;
+        // Drop the closing braces of body and the message to append the
+        // payload's fields. body always has at least a msg_id, so the fields
+        // need a separating comma.
+        line.truncate(line.len() - 2);
+        if !fields.trim().is_empty() {
+            line.push(',');
+            line.push_str(fields);
+        }
+        line.push_str("}}");
+        if let (Some(dedup), Some(in_reply_to)) = (&self.dedup, in_reply_to) {
+            dedup
+                .lock()
+                .unwrap()
+                .record_reply(&msg.dest, in_reply_to, &line);
+        }
+        if let Some(wal) = &self.wal {
+            // Written unbuffered in one go, so lines survive the node being
+            // killed and other nodes appending to the same file can't split them
+            let mut entry = line.clone();
+            entry.push('\n');
+            if let Err(err) = wal.lock().unwrap().write_all(entry.as_bytes()) {
+                eprintln!("ERROR: Failed to write to the message log: {err}");
+            }
+        }
+        self.queue_output(Output::Line(line))
+    }
+
+    // A handler may reply any number of times, e.g. to stream results. Every
+    // message is serialized into a whole line before it is queued for the
+    // writer task, which is the only one writing output, so messages from
+    // concurrent handlers never interleave, and messages sent by one task are
+    // written in the order they were sent.
+    pub fn reply<P, R>(
+        &self,
+        source_msg: &MaelstromMessage<P>,
+        payload: R,
+    ) -> Result<(), GlomerError>
+    where
+        R: Serialize,
+    {
+        self.reply_with_id(source_msg, payload).map(|_| ())
     }
 
-    pub fn reply<P, R>(&self, source_msg: &MaelstromMessage<P>, payload: R)
+    // Same as reply, returning the msg_id of the reply so follow up messages
+    // can refer to it
+    pub fn reply_with_id<P, R>(
+        &self,
+        source_msg: &MaelstromMessage<P>,
+        payload: R,
+    ) -> Result<u64, GlomerError>
     where
         R: Serialize,
     {
+        let msg_id = self.allocate_msg_id();
         self.fire_and_forget(
-            None,
+            msg_id,
             source_msg.body.msg_id,
             source_msg.src.to_string(),
             &payload,
-        );
+        )?;
+        Ok(msg_id)
+    }
+
+    // Reply with an error without returning it, e.g. when one of several
+    // sub-operations failed and handling should carry on. The reply is the
+    // same one run sends when a handler returns err.
+    pub fn reply_error<P>(
+        &self,
+        source_msg: &MaelstromMessage<P>,
+        err: &MaelstromError,
+    ) -> Result<(), GlomerError> {
+        self.reply(source_msg, err)
+    }
+
+    // Reply with a response that carries nothing but its type, e.g.
+    // "broadcast_ok", without declaring a payload variant for it
+    pub fn ack<P>(
+        &self,
+        source_msg: &MaelstromMessage<P>,
+        type_name: &str,
+    ) -> Result<(), GlomerError> {
+        self.reply(source_msg, serde_json::json!({ "type": type_name }))
     }
 
-    pub fn send<P>(&self, dest: &str, payload: P)
+    pub fn send<P>(&self, dest: &str, payload: P) -> Result<(), GlomerError>
     where
         P: Serialize + Debug,
     {
-        // Don't include a msg_id because we aren't expecting a response
-        self.fire_and_forget(None, None, dest.to_string(), &payload);
+        // Still gets a msg_id, even though we aren't expecting a response
+        self.fire_and_forget(self.allocate_msg_id(), None, dest.to_string(), &payload)
     }
 
-    pub async fn send_rpc<P, R>(
-        &self,
-        dest: &str,
-        payload: P,
-        timeout_duration: Option<Duration>,
-    ) -> Result<R, GlomerError>
+    // Same as send with a payload that has already been serialized to a JSON
+    // object, e.g. to serialize a payload sent to many nodes only once
+    pub fn send_raw(&self, dest: &str, body_json: &str) -> Result<(), GlomerError> {
+        self.fire_and_forget_raw(self.allocate_msg_id(), None, dest.to_string(), body_json)
+    }
+
+    // Same as send, addressed to another node by its numeric id
+    pub fn send_to<P>(&self, node: u32, payload: P) -> Result<(), GlomerError>
+    where
+        P: Serialize + Debug,
+    {
+        self.send(&node_id(node), payload)
+    }
+
+    // Same as send_rpc, addressed to another node by its numeric id. Services
+    // like seq-kv still need to be addressed by name with send_rpc.
+    pub async fn send_rpc_to<P, R>(
+        &self,
+        node: u32,
+        payload: P,
+        timeout_duration: Option<Duration>,
+    ) -> Result<R, GlomerError>
+    where
+        P: Serialize + Debug + Send,
+        R: DeserializeOwned + Debug,
+    {
+        self.send_rpc(&node_id(node), payload, timeout_duration)
+            .await
+    }
+
+    pub async fn send_rpc<P, R>(
+        &self,
+        dest: &str,
+        payload: P,
+        timeout_duration: Option<Duration>,
+    ) -> Result<R, GlomerError>
     where
         P: Serialize + Debug + Send,
         R: DeserializeOwned + Debug,
     {
-        let msg_id = self.next_msg_id.fetch_add(1, Ordering::Relaxed);
-        self.fire_and_forget(Some(msg_id), None, dest.to_string(), &payload);
+        self.send_rpc_raw(dest, &serde_json::to_string(&payload)?, timeout_duration)
+            .await
+    }
+
+    // Same as send_rpc with a payload that has already been serialized to a
+    // JSON object, see send_raw
+    pub async fn send_rpc_raw<R>(
+        &self,
+        dest: &str,
+        body_json: &str,
+        timeout_duration: Option<Duration>,
+    ) -> Result<R, GlomerError>
+    where
+        R: DeserializeOwned + Debug,
+    {
+        let (_, response) = self.start_rpc_raw(dest, body_json, timeout_duration)?;
+        response.await
+    }
+
+    // Same as send_rpc, but sends the request right away and returns its
+    // msg_id along with the future for the response, so that the RPC can be
+    // abandoned with cancel_rpc, e.g. once another replica has answered
+    pub fn start_rpc<P, R>(
+        &self,
+        dest: &str,
+        payload: P,
+        timeout_duration: Option<Duration>,
+    ) -> Result<
+        (
+            u64,
+            impl Future<Output = Result<R, GlomerError>> + Send + '_,
+        ),
+        GlomerError,
+    >
+    where
+        P: Serialize + Debug + Send,
+        R: DeserializeOwned + Debug,
+    {
+        self.start_rpc_raw(dest, &serde_json::to_string(&payload)?, timeout_duration)
+    }
+
+    // Stop waiting for the response to the RPC with msg_id, which then fails
+    // with GlomerError::Cancelled. A response that still arrives is ignored.
+    // Returns false if the RPC wasn't pending anymore.
+    pub fn cancel_rpc(&self, msg_id: u64) -> bool {
+        self.response_map.remove(msg_id).is_some()
+    }
+
+    fn start_rpc_raw<R>(
+        &self,
+        dest: &str,
+        body_json: &str,
+        timeout_duration: Option<Duration>,
+    ) -> Result<
+        (
+            u64,
+            impl Future<Output = Result<R, GlomerError>> + Send + '_,
+        ),
+        GlomerError,
+    >
+    where
+        R: DeserializeOwned + Debug,
+    {
+        if self.unreachable.read().unwrap().contains(dest) {
+            return Err(GlomerError::Maelstrom(MaelstromError::node_not_found(
+                format!("{dest} is unreachable"),
+            )));
+        }
+        let timeout_duration = timeout_duration.unwrap_or(self.default_rpc_timeout);
+        let msg_id = self.allocate_msg_id();
         // Set up channel to receive respone
         let (tx, rx) = oneshot::channel();
-        // Store sender on map with msg_id
-        self.response_map.lock().unwrap().insert(msg_id, tx);
+        // Store sender on map with msg_id before sending, so that even a very
+        // fast response finds someone waiting for it
+        if self.response_map.insert(msg_id, tx) == PENDING_RPCS_WARNING {
+            eprintln!("WARN: {PENDING_RPCS_WARNING} RPCs are waiting for a response");
+        }
+        // Removes the entry again however we stop waiting for the response,
+        // including when the response future is dropped before it arrived
+        let pending = PendingRpc {
+            response_map: &self.response_map,
+            msg_id,
+        };
+        self.fire_and_forget_raw(msg_id, None, dest.to_string(), body_json)?;
 
-        if let Some(timeout_duration) = timeout_duration {
+        let response = async move {
+            let _pending = pending;
+            let response = async {
+                if timeout_duration == NO_TIMEOUT {
+                    Ok::<_, GlomerError>(rx.await)
+                } else {
+                    Ok(timeout(timeout_duration, rx).await?)
+                }
+            };
             tokio::select! {
-                () = self.cancellation_token.cancelled() => {
-                    Err(GlomerError::Abort("Node shut down.".into()))
+                () = self.cancellation_token.cancelled() => Err(GlomerError::Cancelled),
+                res = response => match res? {
+                    Ok(response) => parse_response(response),
+                    // Our entry was removed from the response map without a
+                    // response, which only cancel_rpc does
+                    Err(_) => Err(GlomerError::Cancelled),
+                },
+            }
+        };
+        Ok((msg_id, response))
+    }
+
+    // Like send_rpc, but retries transient failures according to the policy.
+    // Each attempt is sent as a new message with a fresh msg_id.
+    pub async fn send_rpc_retry<P, R>(
+        &self,
+        dest: &str,
+        payload: P,
+        policy: &RetryPolicy,
+    ) -> Result<R, GlomerError>
+    where
+        P: Serialize + Debug + Send + Clone,
+        R: DeserializeOwned + Debug,
+    {
+        let mut attempt = 0;
+        loop {
+            match self.send_rpc(dest, payload.clone(), policy.timeout).await {
+                Err(err) if attempt + 1 < policy.max_attempts && policy.is_retryable(&err) => {
+                    if self.sleep(policy.backoff(attempt)).await {
+                        return Err(GlomerError::Cancelled);
+                    }
+                    attempt += 1;
                 }
-                res = timeout(timeout_duration, rx) => {
-                    match res {
-                        Err(_) => {
-                            self.response_map.lock().unwrap().remove(&msg_id);
-                            Err(GlomerError::Timeout)
-                        }
-                        Ok(response) => {
-                            let untagged = serde_json::from_str::<UntaggedRpcMessage<R>>(&response.unwrap())?;
-                            match untagged.body.payload {
-                                UntaggedResult::Ok(payload) => Ok(payload),
-                                UntaggedResult::Err(err) => Err(GlomerError::Maelstrom(err)),
-                            }
-                        }
+                res => return res,
+            }
+        }
+    }
+
+    // Send the same RPC to every destination concurrently and wait for all of
+    // them to finish. Results are returned in order of completion.
+    pub async fn broadcast_rpc<P, R>(
+        &self,
+        dests: &[String],
+        payload: P,
+        timeout_duration: Option<Duration>,
+    ) -> Vec<(String, Result<R, GlomerError>)>
+    where
+        P: Serialize + Debug + Send + Clone + 'static,
+        R: DeserializeOwned + Debug + Send + 'static,
+    {
+        let mut rpcs = self.spawn_rpcs(dests, payload, timeout_duration);
+        let mut results = Vec::with_capacity(dests.len());
+        while let Some(res) = rpcs.join_next().await {
+            match res {
+                Ok(result) => results.push(result),
+                Err(err) => panic::resume_unwind(err.into_panic()),
+            }
+        }
+        results
+    }
+
+    // Send the same RPC to every destination concurrently and collect the
+    // successful responses that arrive within the deadline, e.g. for a best
+    // effort quorum read. Failed RPCs and laggards are left out, the latter
+    // are cancelled once the deadline passes.
+    pub async fn send_and_wait_all<P, R>(
+        &self,
+        dests: &[String],
+        payload: P,
+        deadline: Duration,
+    ) -> Vec<(String, R)>
+    where
+        P: Serialize + Debug + Send + Clone + 'static,
+        R: DeserializeOwned + Debug + Send + 'static,
+    {
+        // Dropping the JoinSet on return aborts the remaining RPCs, which
+        // removes them from the response map
+        let mut rpcs = self.spawn_rpcs(dests, payload, Some(deadline));
+        let mut responses = Vec::with_capacity(dests.len());
+        let collect = async {
+            while let Some(res) = rpcs.join_next().await {
+                match res {
+                    Ok((dest, Ok(response))) => responses.push((dest, response)),
+                    Ok((_, Err(_))) => {}
+                    Err(err) => panic::resume_unwind(err.into_panic()),
+                }
+            }
+        };
+        // Every RPC times out at the deadline too, this only makes sure we
+        // don't wait for a task that hasn't got round to timing out yet
+        let _ = timeout(deadline, collect).await;
+        responses
+    }
+
+    // Sleep for the given duration, waking up early if the node shuts down.
+    // Returns true if the node was shut down.
+    pub async fn sleep(&self, duration: Duration) -> bool {
+        tokio::select! {
+            () = tokio::time::sleep(duration) => false,
+            () = self.cancellation_token.cancelled() => true,
+        }
+    }
+
+    // Call f every interval until the node shuts down, e.g. to gossip. run
+    // waits for the task to stop before returning, so nothing it sends after
+    // the last message handled is lost.
+    pub fn spawn_periodic<F>(&self, interval: Duration, mut f: F)
+    where
+        F: FnMut(&Self) + Send + 'static,
+    {
+        let node = self.clone();
+        self.background.spawn(async move {
+            while !node.sleep(interval).await {
+                f(&node);
+            }
+        });
+    }
+
+    // Every node in the cluster except ourselves
+    #[must_use]
+    pub fn peers(&self) -> Vec<String> {
+        self.node_ids
+            .iter()
+            .filter(|id| id.as_str() != self.id_str())
+            .cloned()
+            .collect()
+    }
+
+    // Send the same message to every other node, without waiting for responses
+    pub fn broadcast_to_peers<P>(&self, payload: P) -> Result<(), GlomerError>
+    where
+        P: Serialize + Debug + Clone,
+    {
+        let body_json = serde_json::to_string(&payload)?;
+        for peer in self.peers() {
+            self.send_raw(&peer, &body_json)?;
+        }
+        Ok(())
+    }
+
+    // Same as broadcast_rpc, sent to every other node
+    pub async fn broadcast_rpc_to_peers<P, R>(
+        &self,
+        payload: P,
+        timeout_duration: Option<Duration>,
+    ) -> Vec<(String, Result<R, GlomerError>)>
+    where
+        P: Serialize + Debug + Send + Clone + 'static,
+        R: DeserializeOwned + Debug + Send + 'static,
+    {
+        self.broadcast_rpc(&self.peers(), payload, timeout_duration)
+            .await
+    }
+
+    // Send the same RPC to every destination concurrently and return the first
+    // successful response. Outstanding RPCs are cancelled once one succeeds,
+    // if they all fail the last error is returned.
+    pub async fn race_rpc<P, R>(
+        &self,
+        dests: &[String],
+        payload: P,
+        timeout_duration: Option<Duration>,
+    ) -> Result<(String, R), GlomerError>
+    where
+        P: Serialize + Debug + Send + Clone + 'static,
+        R: DeserializeOwned + Debug + Send + 'static,
+    {
+        // Dropping the JoinSet on return aborts the remaining RPCs
+        let mut rpcs = self.spawn_rpcs(dests, payload, timeout_duration);
+        let mut last_err = None;
+        while let Some(res) = rpcs.join_next().await {
+            match res {
+                Ok((dest, Ok(response))) => return Ok((dest, response)),
+                Ok((_, Err(err))) => last_err = Some(err),
+                Err(err) => panic::resume_unwind(err.into_panic()),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| GlomerError::Abort("No destinations to send RPC to".into())))
+    }
+
+    // Send the RPC to each candidate in turn until one that exists answers,
+    // e.g. to fall back to another replica. Only NodeNotFound moves on to the
+    // next candidate, any other result is returned along with who sent it.
+    pub async fn send_rpc_any<P, R>(
+        &self,
+        candidates: &[String],
+        payload: P,
+        timeout_duration: Option<Duration>,
+    ) -> Result<(String, R), GlomerError>
+    where
+        P: Serialize + Debug,
+        R: DeserializeOwned + Debug,
+    {
+        let body_json = serde_json::to_string(&payload)?;
+        let mut last_err = None;
+        for candidate in candidates {
+            match self
+                .send_rpc_raw(candidate, &body_json, timeout_duration)
+                .await
+            {
+                Err(
+                    err @ GlomerError::Maelstrom(MaelstromError {
+                        code: ErrorCode::NodeNotFound,
+                        ..
+                    }),
+                ) => last_err = Some(err),
+                res => return res.map(|response| (candidate.clone(), response)),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| GlomerError::Abort("No destinations to send RPC to".into())))
+    }
+
+    fn spawn_rpcs<P, R>(
+        &self,
+        dests: &[String],
+        payload: P,
+        timeout_duration: Option<Duration>,
+    ) -> JoinSet<(String, Result<R, GlomerError>)>
+    where
+        P: Serialize + Debug + Send + Clone + 'static,
+        R: DeserializeOwned + Debug + Send + 'static,
+    {
+        let mut rpcs = JoinSet::new();
+        for dest in dests {
+            let node = self.clone();
+            let dest = dest.clone();
+            let payload = payload.clone();
+            rpcs.spawn(async move {
+                let res = node.send_rpc(&dest, payload, timeout_duration).await;
+                (dest, res)
+            });
+        }
+        rpcs
+    }
+}
+
+// Requests seen by a node with dedup enabled, and the replies sent to them
+#[derive(Debug)]
+struct DedupCache {
+    capacity: usize,
+    replies: BTreeMap<(String, u64), Option<String>>,
+    // Requests in the order they arrived, for eviction
+    order: VecDeque<(String, u64)>,
+}
+
+impl DedupCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            replies: BTreeMap::new(),
+            order: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    // Records the request if it's new, otherwise returns the reply sent to it
+    // (None if it's still being handled)
+    fn check(&mut self, src: &str, msg_id: u64) -> Option<Option<String>> {
+        let key = (src.to_string(), msg_id);
+        if let Some(reply) = self.replies.get(&key) {
+            return Some(reply.clone());
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.replies.remove(&oldest);
+            }
+        }
+        if self.capacity > 0 {
+            self.replies.insert(key.clone(), None);
+            self.order.push_back(key);
+        }
+        None
+    }
+
+    fn record_reply(&mut self, dest: &str, in_reply_to: u64, line: &str) {
+        if let Some(reply) = self.replies.get_mut(&(dest.to_string(), in_reply_to)) {
+            *reply = Some(line.to_string());
+        }
+    }
+}
+
+// Source of incoming messages, stdin unless created through init_with_io
+struct Input(Box<dyn AsyncBufRead + Send + Unpin>);
+
+impl Debug for Input {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Input")
+    }
+}
+
+// Number of independently locked parts of the response map
+const RESPONSE_MAP_SHARDS: usize = 16;
+
+type ResponseSender = oneshot::Sender<MaelstromMessage<Value>>;
+
+// Mapping from msg_id to the channel awaiting its response. Split into shards
+// by msg_id, so concurrent RPCs (with consecutive msg_ids) rarely wait for the
+// same lock.
+#[derive(Debug, Default)]
+pub(super) struct ResponseMap {
+    shards: [Mutex<BTreeMap<u64, ResponseSender>>; RESPONSE_MAP_SHARDS],
+    // Entries across all shards, only changed while holding a shard lock
+    len: AtomicUsize,
+}
+
+impl ResponseMap {
+    // Also used when dropping a PendingRpc, so a poisoned lock mustn't panic
+    fn shard(&self, msg_id: u64) -> MutexGuard<'_, BTreeMap<u64, ResponseSender>> {
+        // The remainder always fits in a usize
+        #[allow(clippy::cast_possible_truncation)]
+        let shard = &self.shards[(msg_id % RESPONSE_MAP_SHARDS as u64) as usize];
+        shard.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Returns the number of entries after inserting
+    fn insert(&self, msg_id: u64, tx: ResponseSender) -> usize {
+        let mut shard = self.shard(msg_id);
+        if shard.insert(msg_id, tx).is_none() {
+            self.len.fetch_add(1, Ordering::Relaxed) + 1
+        } else {
+            self.len.load(Ordering::Relaxed)
+        }
+    }
+
+    fn remove(&self, msg_id: u64) -> Option<ResponseSender> {
+        let removed = self.shard(msg_id).remove(&msg_id);
+        if removed.is_some() {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        removed
+    }
+
+    fn contains(&self, msg_id: u64) -> bool {
+        self.shard(msg_id).contains_key(&msg_id)
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+}
+
+// Entry in the response map for an RPC that is still waiting for its response
+struct PendingRpc<'a> {
+    response_map: &'a ResponseMap,
+    msg_id: u64,
+}
+
+impl Drop for PendingRpc<'_> {
+    fn drop(&mut self) {
+        // No-op if the response was already delivered
+        self.response_map.remove(self.msg_id);
+    }
+}
+
+#[derive(Debug)]
+enum Output {
+    Line(String),
+    // Sent once everything queued before it has been written and flushed
+    Flushed(oneshot::Sender<()>),
+}
+
+// Writer task - the only place that touches stdout, so concurrent handlers can
+// never interleave partial lines and messages are written in the order sent
+async fn write_output<W>(
+    writer: W,
+    mut rx: mpsc::UnboundedReceiver<Output>,
+    flush_interval: Duration,
+    mut rate_limiter: Option<RateLimiter>,
+) where
+    W: AsyncWrite + Unpin,
+{
+    let mut writer = BufWriter::with_capacity(OUTPUT_BUFFER_SIZE, writer);
+    // When buffered output has to be written out, None if nothing is buffered
+    let mut flush_deadline = None;
+    loop {
+        let output = match flush_deadline {
+            Some(deadline) => tokio::select! {
+                output = rx.recv() => output,
+                () = tokio::time::sleep_until(deadline) => {
+                    flush_deadline = None;
+                    if let Err(err) = writer.flush().await {
+                        eprintln!("ERROR: Failed to write output: {err}");
+                        break;
                     }
+                    continue;
+                }
+            },
+            None => rx.recv().await,
+        };
+        let Some(output) = output else {
+            break;
+        };
+        let res = match output {
+            Output::Line(mut line) => {
+                let admitted = match &mut rate_limiter {
+                    Some(limiter) => limiter.admit(&mut writer).await,
+                    None => Ok(true),
+                };
+                let res = match admitted {
+                    Ok(true) => {
+                        line.push('\n');
+                        writer.write_all(line.as_bytes()).await
+                    }
+                    Ok(false) => {
+                        eprintln!("INFO: Rate limit exceeded, dropping message: {line}");
+                        Ok(())
+                    }
+                    Err(err) => Err(err),
+                };
+                match res {
+                    // Nothing else is waiting to be written, send what we have
+                    Ok(()) if flush_interval.is_zero() && rx.is_empty() => writer.flush().await,
+                    Ok(()) if !flush_interval.is_zero() => {
+                        flush_deadline.get_or_insert_with(|| Instant::now() + flush_interval);
+                        Ok(())
+                    }
+                    res => res,
                 }
             }
+            Output::Flushed(tx) => {
+                flush_deadline = None;
+                let res = writer.flush().await;
+                let _ = tx.send(());
+                res
+            }
+        };
+        // Output is unusable from here on, e.g. stdout was closed
+        if let Err(err) = res {
+            eprintln!("ERROR: Failed to write output: {err}");
+            break;
+        }
+    }
+}
+
+// Token bucket holding up to one second's worth of messages, refilled
+// continuously at the configured rate
+struct RateLimiter {
+    per_second: f64,
+    tokens: f64,
+    last_refill: Instant,
+    mode: RateLimitMode,
+}
+
+impl RateLimiter {
+    fn new(per_second: u32, mode: RateLimitMode) -> Self {
+        Self {
+            per_second: f64::from(per_second),
+            tokens: f64::from(per_second),
+            last_refill: Instant::now(),
+            mode,
+        }
+    }
+
+    // Takes a token, or returns how long until the next one is available
+    fn try_take(&mut self) -> Result<(), Duration> {
+        let now = Instant::now();
+        let refilled = now.duration_since(self.last_refill).as_secs_f64() * self.per_second;
+        self.tokens = (self.tokens + refilled).min(self.per_second);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
         } else {
-            let untagged = serde_json::from_str::<UntaggedRpcMessage<R>>(&rx.await.unwrap())?;
-            match untagged.body.payload {
-                UntaggedResult::Ok(payload) => Ok(payload),
-                UntaggedResult::Err(err) => Err(GlomerError::Maelstrom(err)),
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.per_second,
+            ))
+        }
+    }
+
+    // Whether the next message may be written. Output that is already
+    // buffered is written out before waiting for a token, so it isn't held
+    // back along with the message that is over the limit.
+    async fn admit<W>(&mut self, writer: &mut W) -> std::io::Result<bool>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        loop {
+            match self.try_take() {
+                Ok(()) => return Ok(true),
+                Err(_) if self.mode == RateLimitMode::Drop => return Ok(false),
+                Err(wait) => {
+                    writer.flush().await?;
+                    tokio::time::sleep(wait).await;
+                }
             }
         }
     }
 }
 
+fn parse_response<R>(response: MaelstromMessage<Value>) -> Result<R, GlomerError>
+where
+    R: DeserializeOwned,
+{
+    match UntaggedResult::<R>::deserialize(&response.body.payload) {
+        Ok(UntaggedResult::Ok(payload)) => Ok(payload),
+        Ok(UntaggedResult::Err(err)) => Err(GlomerError::Maelstrom(err)),
+        Err(_) => Err(GlomerError::UnexpectedResponse {
+            raw: response.body.payload.to_string(),
+            expected: std::any::type_name::<R>(),
+        }),
+    }
+}
+
+// Since using Result<P, MaelstromError> results in a tagged json object,
+// we need to use an untagged version of Result and then convert.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(untagged)]
 enum UntaggedResult<P> {
@@ -258,12 +1721,564 @@ enum UntaggedResult<P> {
     Err(MaelstromError),
 }
 
-// Intermediate type for serialization/deserialization
-// Since using Result<P, MaelstromError> results in a tagged json object,
-// we need to use an untagged version of Result and then convert.
-#[derive(Serialize, Deserialize, Clone, Debug)]
-struct UntaggedRpcMessage<P> {
+// Just enough of a message to reply to it or route it, see
+// Node::reject_long_line and Node::is_pending_response
+#[derive(Deserialize)]
+struct Envelope {
     src: String,
-    dest: String,
-    body: Body<UntaggedResult<P>>,
+    body: EnvelopeBody,
+}
+
+#[derive(Deserialize)]
+struct EnvelopeBody {
+    msg_id: Option<u64>,
+    in_reply_to: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::{json, Value};
+    use tokio::{
+        io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader, DuplexStream, Lines},
+        time::{timeout, Duration, Instant},
+    };
+
+    use super::{Handler, Node, NodeBuilder};
+    use crate::{
+        error::{ErrorCode, GlomerError, MaelstromError},
+        maelstrom_payload,
+        node::testing::{init_line, Simulator, TestHarness},
+        MaelstromMessage, RawMessage,
+    };
+
+    fn parse(lines: &[String]) -> Vec<Value> {
+        lines
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn request(msg_id: u64, body: Value) -> String {
+        let mut body = body;
+        body["msg_id"] = msg_id.into();
+        json!({"src": "c1", "dest": "n1", "body": body}).to_string()
+    }
+
+    // Initializes n1 of n1 and n2, returning its input, which closing shuts
+    // the node down, and its output from init_ok on
+    async fn init_node(
+        builder: NodeBuilder,
+    ) -> (Node, DuplexStream, Lines<BufReader<DuplexStream>>) {
+        let (node_input, mut input) = tokio::io::duplex(64 * 1024);
+        let (node_output, output) = tokio::io::duplex(64 * 1024);
+        let mut init = init_line("n1", &["n1".into(), "n2".into()]);
+        init.push('\n');
+        input.write_all(init.as_bytes()).await.unwrap();
+        let node = builder
+            .init_with_io(BufReader::new(node_input), node_output)
+            .await
+            .unwrap();
+        (node, input, BufReader::new(output).lines())
+    }
+
+    // Next line of output, panics if there is none within a second
+    async fn next_message(output: &mut Lines<BufReader<DuplexStream>>) -> Value {
+        let line = timeout(Duration::from_secs(1), output.next_line())
+            .await
+            .expect("Node didn't write anything")
+            .unwrap()
+            .expect("Node closed its output");
+        serde_json::from_str(&line).unwrap()
+    }
+
+    maelstrom_payload! {
+        request enum Request {
+            Echo { echo: Value },
+        }
+    }
+
+    // Only knows echo, anything else ends up in handle_unknown
+    struct Echo(Node);
+
+    impl Handler<Request> for Echo {
+        async fn handle(&self, msg: &MaelstromMessage<Request>) -> Result<(), MaelstromError> {
+            let Request::Echo { echo } = &msg.body.payload;
+            self.0
+                .reply(msg, json!({"type": "echo_ok", "echo": echo}))?;
+            Ok(())
+        }
+    }
+
+    // Same as Echo, also answering ping messages from outside its enum
+    struct Fallback(Node);
+
+    impl Handler<Request> for Fallback {
+        async fn handle(&self, msg: &MaelstromMessage<Request>) -> Result<(), MaelstromError> {
+            Echo(self.0.clone()).handle(msg).await
+        }
+
+        async fn handle_unknown(&self, msg: &RawMessage) -> Result<(), MaelstromError> {
+            if msg.payload_type() != Some("ping") {
+                return Err(MaelstromError::not_supported("Not a ping"));
+            }
+            let payload = msg.body.payload["payload"].clone();
+            self.0
+                .reply(msg, json!({"type": "pong", "payload": payload}))?;
+            Ok(())
+        }
+    }
+
+    // Races a ping to every peer, of which only n2 answers straight away
+    struct Racer(Node);
+
+    impl Handler<Value> for Racer {
+        async fn handle(&self, msg: &MaelstromMessage<Value>) -> Result<(), MaelstromError> {
+            match msg.body.payload["type"].as_str() {
+                Some("race") => {
+                    let (winner, _): (_, Value) = self
+                        .0
+                        .race_rpc(&self.0.peers(), json!({"type": "ping"}), None)
+                        .await?;
+                    self.0
+                        .reply(msg, json!({"type": "race_ok", "winner": winner}))?;
+                }
+                Some("ping") => {
+                    if self.0.id != 2 && self.0.sleep(Duration::from_secs(1)).await {
+                        return Ok(());
+                    }
+                    self.0.ack(msg, "pong")?;
+                }
+                _ => {
+                    let pending = self.0.pending_rpcs();
+                    self.0
+                        .reply(msg, json!({"type": "pending_ok", "pending": pending}))?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    // Replies to every request with echo_ok, then lets the test know
+    // Signals once it has started, then only replies once the node is
+    // shutting down
+    struct Linger(Node, Arc<tokio::sync::Notify>);
+
+    impl Handler<Value> for Linger {
+        async fn handle(&self, msg: &MaelstromMessage<Value>) -> Result<(), MaelstromError> {
+            self.1.notify_one();
+            assert!(self.0.sleep(Duration::from_secs(3600)).await);
+            self.0.ack(msg, "echo_ok")?;
+            Ok(())
+        }
+    }
+
+    // Only replies once the gate is closed
+    struct Gated(Node, Arc<tokio::sync::Semaphore>);
+
+    impl Handler<Value> for Gated {
+        async fn handle(&self, msg: &MaelstromMessage<Value>) -> Result<(), MaelstromError> {
+            let _ = self.1.acquire().await;
+            self.0.ack(msg, "echo_ok")?;
+            Ok(())
+        }
+    }
+
+    // Forwards every request to n2 and relays the response
+    struct Forward(Node);
+
+    impl Handler<Value> for Forward {
+        async fn handle(&self, msg: &MaelstromMessage<Value>) -> Result<(), MaelstromError> {
+            let response: Value = self.0.send_rpc("n2", json!({"type": "ping"}), None).await?;
+            self.0.reply(msg, response)?;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_cancels_pending_rpcs_quietly() {
+        let output = TestHarness::new("n1", vec!["n1".into(), "n2".into()])
+            .input(request(1, json!({"type": "forward"})))
+            .run(Forward)
+            .await
+            .unwrap();
+        let output = parse(&output);
+        // init_ok and the ping, but no error for the client
+        assert_eq!(output.len(), 2, "{output:#?}");
+        assert_eq!(output[1]["dest"], "n2");
+        assert_eq!(output[1]["body"]["type"], "ping");
+    }
+
+    #[tokio::test]
+    async fn unknown_type_is_answered_with_an_error() {
+        let output = TestHarness::new("n1", vec!["n1".into()])
+            .input(request(1, json!({"type": "bogus"})))
+            .input(request(2, json!({"type": "echo", "echo": "still running"})))
+            .run(Echo)
+            .await
+            .unwrap();
+        let output = parse(&output);
+        assert_eq!(output.len(), 3, "{output:#?}");
+        assert_eq!(output[1]["dest"], "c1");
+        assert_eq!(output[1]["body"]["type"], "error");
+        assert_eq!(output[1]["body"]["in_reply_to"], 1);
+        assert_eq!(output[1]["body"]["code"], ErrorCode::NotSupported.raw());
+        assert_eq!(output[2]["body"]["type"], "echo_ok");
+        assert_eq!(output[2]["body"]["in_reply_to"], 2);
+    }
+
+    #[tokio::test]
+    async fn unknown_types_go_to_handle_unknown() {
+        let output = TestHarness::new("n1", vec!["n1".into()])
+            .input(request(1, json!({"type": "ping", "payload": [1, 2]})))
+            .input(request(2, json!({"type": "echo", "echo": 3})))
+            .input(request(3, json!({"type": "bogus"})))
+            .run(Fallback)
+            .await
+            .unwrap();
+        let output = parse(&output);
+        assert_eq!(output.len(), 4, "{output:#?}");
+        assert_eq!(output[1]["body"]["type"], "pong");
+        assert_eq!(output[1]["body"]["in_reply_to"], 1);
+        assert_eq!(output[1]["body"]["payload"], json!([1, 2]));
+        assert_eq!(output[2]["body"]["type"], "echo_ok");
+        assert_eq!(output[3]["body"]["type"], "error");
+        assert_eq!(output[3]["body"]["in_reply_to"], 3);
+    }
+
+    #[tokio::test]
+    async fn race_rpc_returns_the_fastest_response() {
+        let cluster = Simulator::new(4).start(Racer);
+        let start = Instant::now();
+        let reply = cluster.rpc(0, json!({"type": "race"})).await.unwrap();
+        assert_eq!(reply["winner"], "n2");
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        // The RPCs to the slow nodes are cancelled rather than left to time out
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let reply = cluster.rpc(0, json!({"type": "pending"})).await.unwrap();
+        assert_eq!(reply["pending"], 0);
+        cluster.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn finished_rpcs_leave_no_pending_entries() {
+        let (node, _input, _output) = init_node(Node::builder()).await;
+        let ping = || json!({"type": "ping"});
+
+        let res: Result<Value, _> = node
+            .send_rpc("n2", ping(), Some(Duration::from_millis(10)))
+            .await;
+        assert!(matches!(res, Err(GlomerError::Timeout)), "{res:?}");
+        assert_eq!(node.pending_rpcs(), 0);
+
+        // Dropped without waiting for the response
+        let (_, response) = node.start_rpc::<_, Value>("n2", ping(), None).unwrap();
+        assert_eq!(node.pending_rpcs(), 1);
+        drop(response);
+        assert_eq!(node.pending_rpcs(), 0);
+
+        // Cancelled by the node shutting down
+        let (_, response) = node.start_rpc::<_, Value>("n2", ping(), None).unwrap();
+        node.cancellation_token.cancel();
+        let res = response.await;
+        assert!(matches!(res, Err(GlomerError::Cancelled)), "{res:?}");
+        assert_eq!(node.pending_rpcs(), 0);
+    }
+
+    #[tokio::test]
+    async fn dropped_response_sender_fails_cleanly() {
+        let (node, _input, _output) = init_node(Node::builder()).await;
+        let (msg_id, response) = node
+            .start_rpc::<_, Value>("n2", json!({"type": "ping"}), None)
+            .unwrap();
+        // What happens when the entry goes away without a response, e.g. in a
+        // race between shutdown and the response arriving
+        drop(node.response_map.remove(msg_id));
+        let res = response.await;
+        assert!(matches!(res, Err(GlomerError::Cancelled)), "{res:?}");
+    }
+
+    #[tokio::test]
+    async fn blank_lines_are_skipped() {
+        let output = TestHarness::new("n1", vec!["n1".into()])
+            .input("")
+            .input(request(1, json!({"type": "echo", "echo": 1})))
+            .input("  \t ")
+            .input("\r")
+            .input(request(2, json!({"type": "echo", "echo": 2})) + "  \r")
+            .input("")
+            .run(Echo)
+            .await
+            .unwrap();
+        let output = parse(&output);
+        assert_eq!(output.len(), 3, "{output:#?}");
+        assert_eq!(output[1]["body"]["echo"], 1);
+        assert_eq!(output[2]["body"]["echo"], 2);
+    }
+
+    #[tokio::test]
+    async fn output_queued_before_shutdown_is_written() {
+        // Nothing is flushed on its own after init_ok, only on shutdown
+        let builder = Node::builder().flush_interval(Duration::from_secs(3600));
+        let (node, mut input, mut output) = init_node(builder).await;
+        let started = Arc::new(tokio::sync::Notify::new());
+        let run = tokio::spawn({
+            let node = node.clone();
+            let handler = Linger(node.clone(), started.clone());
+            async move { node.run(handler).await }
+        });
+
+        assert_eq!(next_message(&mut output).await["body"]["type"], "init_ok");
+        let mut line = request(1, json!({"type": "echo"}));
+        line.push('\n');
+        input.write_all(line.as_bytes()).await.unwrap();
+        timeout(Duration::from_secs(5), started.notified())
+            .await
+            .unwrap();
+        // The handler is still running when input ends
+        drop(input);
+        run.await.unwrap().unwrap();
+
+        let reply = next_message(&mut output).await;
+        assert_eq!(reply["body"]["type"], "echo_ok");
+        assert_eq!(reply["body"]["in_reply_to"], 1);
+    }
+
+    #[tokio::test]
+    async fn reading_stops_while_handlers_are_at_the_limit() {
+        let builder = Node::builder().max_concurrency(1);
+        let (node, mut input, mut output) = init_node(builder).await;
+        let gate = Arc::new(tokio::sync::Semaphore::new(0));
+        let run = tokio::spawn({
+            let node = node.clone();
+            let handler = Gated(node.clone(), gate.clone());
+            async move { node.run(handler).await }
+        });
+
+        assert_eq!(next_message(&mut output).await["body"]["type"], "init_ok");
+        // Far more than the pipe holds, so this only completes if the node
+        // keeps reading while its one handler is still running
+        let burst: String = (1..10_000)
+            .map(|msg_id| request(msg_id, json!({"type": "echo"})) + "\n")
+            .collect();
+        let write = timeout(
+            Duration::from_millis(200),
+            input.write_all(burst.as_bytes()),
+        )
+        .await;
+        assert!(write.is_err(), "Node kept reading past the limit");
+
+        gate.close();
+        let reply = next_message(&mut output).await;
+        assert_eq!(reply["body"]["in_reply_to"], 1);
+        drop(input);
+        // Replies to whatever else was read would fill the pipe otherwise
+        tokio::spawn(async move { while let Ok(Some(_)) = output.next_line().await {} });
+        run.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn responses_skip_the_concurrency_limit() {
+        let builder = Node::builder().max_concurrency(1);
+        let (node, mut input, mut output) = init_node(builder).await;
+        let run = tokio::spawn({
+            let node = node.clone();
+            async move { node.run(Forward(node.clone())).await }
+        });
+
+        assert_eq!(next_message(&mut output).await["body"]["type"], "init_ok");
+        let mut line = request(1, json!({"type": "echo"}));
+        line.push('\n');
+        input.write_all(line.as_bytes()).await.unwrap();
+        let ping = next_message(&mut output).await;
+        assert_eq!(ping["body"]["type"], "ping");
+        // The forwarding handler holds the only permit until this arrives
+        let mut line = json!({
+            "src": "n2",
+            "dest": "n1",
+            "body": {"type": "pong", "msg_id": 1, "in_reply_to": ping["body"]["msg_id"]},
+        })
+        .to_string();
+        line.push('\n');
+        input.write_all(line.as_bytes()).await.unwrap();
+
+        let reply = next_message(&mut output).await;
+        assert_eq!(reply["body"]["type"], "pong");
+        assert_eq!(reply["body"]["in_reply_to"], 1);
+        drop(input);
+        run.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn in_reply_to_without_pending_rpc_is_handled() {
+        let output = TestHarness::new("n1", vec!["n1".into()])
+            .input(request(
+                1,
+                json!({"type": "echo", "echo": "hi", "in_reply_to": 7}),
+            ))
+            // Doesn't parse as a request either, so most likely a response
+            // that arrived too late, which mustn't be answered with an error
+            .input(request(2, json!({"type": "late_ok", "in_reply_to": 8})))
+            .run(Echo)
+            .await
+            .unwrap();
+        let output = parse(&output);
+        assert_eq!(output.len(), 2, "{output:#?}");
+        assert_eq!(output[1]["body"]["type"], "echo_ok");
+        assert_eq!(output[1]["body"]["in_reply_to"], 1);
+        assert_eq!(output[1]["body"]["echo"], "hi");
+    }
+
+    #[tokio::test]
+    async fn message_in_same_write_as_init_is_handled() {
+        let (node_input, mut input) = tokio::io::duplex(64 * 1024);
+        let (node_output, output) = tokio::io::duplex(64 * 1024);
+        // Both lines end up in the reader's buffer when init reads the first
+        let both = format!(
+            "{}\n{}\n",
+            init_line("n1", &["n1".into()]),
+            request(1, json!({"type": "echo", "echo": "first"}))
+        );
+        input.write_all(both.as_bytes()).await.unwrap();
+        let node = Node::init_with_io(BufReader::new(node_input), node_output)
+            .await
+            .unwrap();
+        let run = tokio::spawn({
+            let node = node.clone();
+            async move { node.run(Echo(node.clone())).await }
+        });
+
+        let mut output = BufReader::new(output).lines();
+        assert_eq!(next_message(&mut output).await["body"]["type"], "init_ok");
+        let reply = next_message(&mut output).await;
+        assert_eq!(reply["body"]["type"], "echo_ok");
+        assert_eq!(reply["body"]["echo"], "first");
+        drop(input);
+        run.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelled_rpc_fails_with_cancelled() {
+        let (node, mut input, mut output) = init_node(Node::builder()).await;
+        let run = tokio::spawn({
+            let node = node.clone();
+            async move { node.run(Echo(node.clone())).await }
+        });
+        assert_eq!(next_message(&mut output).await["body"]["type"], "init_ok");
+
+        let (msg_id, response) = node
+            .start_rpc::<_, Value>("n2", json!({"type": "ping"}), None)
+            .unwrap();
+        assert_eq!(next_message(&mut output).await["body"]["msg_id"], msg_id);
+        assert!(node.cancel_rpc(msg_id));
+        assert!(!node.cancel_rpc(msg_id));
+        let res = response.await;
+        assert!(matches!(res, Err(GlomerError::Cancelled)), "{res:?}");
+        assert_eq!(node.pending_rpcs(), 0);
+
+        // The response still arriving is dropped, rather than answered
+        let late = json!({
+            "src": "n2",
+            "dest": "n1",
+            "body": {"type": "pong", "in_reply_to": msg_id},
+        });
+        let lines = format!(
+            "{late}\n{}\n",
+            request(1, json!({"type": "echo", "echo": 1}))
+        );
+        input.write_all(lines.as_bytes()).await.unwrap();
+        let reply = next_message(&mut output).await;
+        assert_eq!(reply["body"]["type"], "echo_ok");
+        drop(input);
+        run.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn requests_read_before_eof_are_answered() {
+        const REQUESTS: u64 = 100;
+        let (node, mut input, mut output) = init_node(Node::builder()).await;
+        // Every other request is answered with an error
+        let burst: String = (1..=REQUESTS)
+            .map(|msg_id| {
+                let msg_type = if msg_id % 2 == 0 { "echo" } else { "bogus" };
+                request(msg_id, json!({"type": msg_type, "echo": msg_id})) + "\n"
+            })
+            .collect();
+        input.write_all(burst.as_bytes()).await.unwrap();
+        // Input ends right after the burst, before most of it has been handled
+        drop(input);
+        node.run(Echo(node.clone())).await.unwrap();
+
+        assert_eq!(next_message(&mut output).await["body"]["type"], "init_ok");
+        let mut answered = Vec::new();
+        for _ in 1..=REQUESTS {
+            answered.push(next_message(&mut output).await["body"]["in_reply_to"].clone());
+        }
+        answered.sort_by_key(|msg_id| msg_id.as_u64());
+        assert_eq!(
+            answered,
+            (1..=REQUESTS).map(Value::from).collect::<Vec<_>>()
+        );
+    }
+
+    // Compares the sharded response map with a single locked map under the
+    // access pattern of concurrent RPCs: consecutive msg_ids, each inserted
+    // on send and removed once its response arrives. Only meaningful with
+    // several cores, run with
+    // cargo test --release --lib response_map_contention -- --ignored --nocapture
+    #[test]
+    #[ignore = "benchmark"]
+    fn response_map_contention() {
+        use std::{
+            collections::BTreeMap,
+            sync::{
+                atomic::{AtomicU64, Ordering},
+                Mutex,
+            },
+            thread,
+        };
+
+        use tokio::sync::oneshot;
+
+        use super::{ResponseMap, ResponseSender};
+
+        const OPS_PER_THREAD: u64 = 200_000;
+
+        fn contend(threads: usize, rpc: impl Fn(u64) + Sync) -> Duration {
+            let next_msg_id = AtomicU64::new(0);
+            let start = Instant::now();
+            thread::scope(|scope| {
+                for _ in 0..threads {
+                    scope.spawn(|| {
+                        for _ in 0..OPS_PER_THREAD {
+                            rpc(next_msg_id.fetch_add(1, Ordering::Relaxed));
+                        }
+                    });
+                }
+            });
+            start.elapsed()
+        }
+
+        let threads = thread::available_parallelism().map_or(8, usize::from);
+        let sharded = ResponseMap::default();
+        let sharded_time = contend(threads, |msg_id| {
+            sharded.insert(msg_id, oneshot::channel().0);
+            sharded.remove(msg_id).unwrap();
+        });
+        assert_eq!(sharded.len(), 0);
+
+        let single: Mutex<BTreeMap<u64, ResponseSender>> = Mutex::new(BTreeMap::new());
+        let single_time = contend(threads, |msg_id| {
+            single.lock().unwrap().insert(msg_id, oneshot::channel().0);
+            single.lock().unwrap().remove(&msg_id).unwrap();
+        });
+        assert!(single.lock().unwrap().is_empty());
+
+        eprintln!(
+            "{threads} threads, {OPS_PER_THREAD} RPCs each: sharded {sharded_time:?}, single lock \
+             {single_time:?}"
+        );
+    }
 }
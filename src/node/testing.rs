@@ -0,0 +1,471 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+    io, panic,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    task::{Context, Poll},
+};
+
+use rand::Rng as _;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncWrite, AsyncWriteExt as _, BufReader, DuplexStream},
+    sync::{mpsc, oneshot},
+    task::JoinSet,
+    time::{timeout, Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    clock::Clock,
+    error::GlomerError,
+    node::{node_id, parse_node_id, Handler, Node},
+};
+
+// Drives a handler from a fixed list of input lines instead of stdin, and
+// captures everything the node writes instead of printing it to stdout. The
+// init message is generated, so the input only needs the requests under test.
+//
+// Lines are written one at a time, each once the node has stopped writing
+// output for SETTLE_INTERVAL, so e.g. a response to an RPC is only sent after
+// the handler sent the request. Input ends once the node has settled after
+// the last line, which shuts it down like stdin closing would. RPCs still
+// waiting on a response at that point (e.g. from a kv service or another
+// node) fail with GlomerError::Cancelled, but the handlers waiting on them
+// run to completion.
+#[derive(Debug, Clone)]
+pub struct TestHarness {
+    node_id: String,
+    node_ids: Vec<String>,
+    input: Vec<String>,
+    time_source: Clock,
+}
+
+impl TestHarness {
+    #[must_use]
+    pub fn new(node_id: impl Into<String>, node_ids: Vec<String>) -> Self {
+        Self {
+            node_id: node_id.into(),
+            node_ids,
+            input: Vec::new(),
+            time_source: Clock::System,
+        }
+    }
+
+    // Run the node with this time source, e.g. a Clock::mock the test keeps a
+    // clone of to control time
+    #[must_use]
+    pub fn time_source(mut self, time_source: Clock) -> Self {
+        self.time_source = time_source;
+        self
+    }
+
+    // Queue a JSON encoded message, inputs are processed in the order given
+    #[must_use]
+    pub fn input(mut self, line: impl Into<String>) -> Self {
+        self.input.push(line.into());
+        self
+    }
+
+    // Initialize a node, build the handler from it and run until all input has
+    // been processed. Returns every line the node wrote, starting with its
+    // reply to the init message.
+    pub async fn run<P, H, F>(self, make_handler: F) -> Result<Vec<String>, GlomerError>
+    where
+        P: DeserializeOwned + Debug + Send + Sync + 'static,
+        H: Handler<P> + Send + Sync + 'static,
+        F: FnOnce(Node) -> H,
+    {
+        let (node_input, mut input) = tokio::io::duplex(CHANNEL_CAPACITY);
+        let mut init = init_line(&self.node_id, &self.node_ids);
+        init.push('\n');
+        input.write_all(init.as_bytes()).await?;
+
+        let output = CapturedOutput::default();
+        let node = Node::builder()
+            .time_source(self.time_source)
+            .init_with_io(BufReader::new(node_input), output.clone())
+            .await?;
+        let run = node.run(make_handler(node.clone()));
+
+        let feed = async {
+            for mut line in self.input {
+                line.push('\n');
+                input.write_all(line.as_bytes()).await?;
+                output.settled().await;
+            }
+            // Closes the node's input
+            drop(input);
+            Ok::<_, GlomerError>(())
+        };
+        let (run, feed) = tokio::join!(run, feed);
+        run?;
+        feed?;
+
+        let bytes = output.0.lock().unwrap();
+        Ok(String::from_utf8_lossy(&bytes)
+            .lines()
+            .map(str::to_string)
+            .collect())
+    }
+}
+
+// Output sink that can still be read once the writer task owns a clone of it
+#[derive(Debug, Clone, Default)]
+struct CapturedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl CapturedOutput {
+    // Wait until nothing has been written for SETTLE_INTERVAL
+    async fn settled(&self) {
+        let mut written = self.0.lock().unwrap().len();
+        loop {
+            tokio::time::sleep(SETTLE_INTERVAL).await;
+            let now = self.0.lock().unwrap().len();
+            if now == written {
+                return;
+            }
+            written = now;
+        }
+    }
+}
+
+impl AsyncWrite for CapturedOutput {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Runs a cluster of nodes in process, routing messages between them by dest
+// like Maelstrom's network does. Messages between nodes can be delayed (and
+// thereby reordered) or dropped.
+#[derive(Debug, Clone)]
+pub struct Simulator {
+    node_count: u32,
+    max_delay: Duration,
+    drop_rate: f64,
+}
+
+impl Simulator {
+    // Nodes are named n0 to n{node_count - 1}
+    #[must_use]
+    pub const fn new(node_count: u32) -> Self {
+        Self {
+            node_count,
+            max_delay: Duration::ZERO,
+            drop_rate: 0.0,
+        }
+    }
+
+    // Delay every message between nodes by a random duration up to max_delay
+    #[must_use]
+    pub const fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    // Drop every message between nodes with the given probability, messages
+    // to and from clients are always delivered
+    #[must_use]
+    pub fn with_drop_rate(mut self, drop_rate: f64) -> Self {
+        self.drop_rate = drop_rate.clamp(0.0, 1.0);
+        self
+    }
+
+    // Initialize every node, building its handler with make_handler
+    pub fn start<P, H, F>(self, make_handler: F) -> Cluster
+    where
+        P: DeserializeOwned + Debug + Send + Sync + 'static,
+        H: Handler<P> + Send + Sync + 'static,
+        F: Fn(Node) -> H + Send + Sync + 'static,
+    {
+        let node_ids: Vec<_> = (0..self.node_count).map(node_id).collect();
+        let make_handler = Arc::new(make_handler);
+        let replies: Arc<PendingReplies> = Arc::default();
+        let mut inputs = Vec::new();
+        let mut receivers = Vec::new();
+        for _ in &node_ids {
+            let (tx, rx) = mpsc::unbounded_channel::<String>();
+            inputs.push(tx);
+            receivers.push(rx);
+        }
+
+        // Routes hold on to every node's input, so dropping ours doesn't
+        // close them, shutdown cancels this instead
+        let shutdown = CancellationToken::new();
+        let mut nodes = JoinSet::new();
+        let mut unreachable = Vec::new();
+        for (id, mut rx) in node_ids.iter().zip(receivers) {
+            let (node_input, mut network_input) = tokio::io::duplex(CHANNEL_CAPACITY);
+            let (node_output, network_output) = tokio::io::duplex(CHANNEL_CAPACITY);
+
+            let init = init_line(id, &node_ids);
+            let node_shutdown = shutdown.clone();
+            // Dropping network_input on return is what the node sees as EOF
+            tokio::spawn(async move {
+                let mut line = init;
+                loop {
+                    line.push('\n');
+                    if network_input.write_all(line.as_bytes()).await.is_err() {
+                        break;
+                    }
+                    tokio::select! {
+                        next = rx.recv() => match next {
+                            Some(next) => line = next,
+                            None => break,
+                        },
+                        () = node_shutdown.cancelled() => break,
+                    }
+                }
+            });
+
+            let network = Network {
+                simulator: self.clone(),
+                inputs: inputs.clone(),
+                replies: replies.clone(),
+            };
+            tokio::spawn(network.route(network_output));
+
+            let make_handler = make_handler.clone();
+            let node_unreachable: Arc<RwLock<BTreeSet<String>>> = Arc::default();
+            unreachable.push(node_unreachable.clone());
+            nodes.spawn(async move {
+                let node = Node::builder()
+                    .unreachable(node_unreachable)
+                    .init_with_io(BufReader::new(node_input), node_output)
+                    .await?;
+                node.run(make_handler(node.clone())).await
+            });
+        }
+
+        Cluster {
+            inputs,
+            shutdown,
+            nodes,
+            replies,
+            unreachable,
+            next_msg_id: 0.into(),
+        }
+    }
+}
+
+// A running Simulator
+#[derive(Debug)]
+pub struct Cluster {
+    inputs: Vec<mpsc::UnboundedSender<String>>,
+    // Closes every node's input
+    shutdown: CancellationToken,
+    nodes: JoinSet<Result<(), GlomerError>>,
+    replies: Arc<PendingReplies>,
+    // What each node considers unreachable, see Node::set_unreachable
+    unreachable: Vec<Arc<RwLock<BTreeSet<String>>>>,
+    next_msg_id: AtomicU64,
+}
+
+impl Cluster {
+    // Make RPCs from node to the given nodes fail with NodeNotFound, until
+    // called again. Only affects RPCs, so partition both directions to
+    // separate two nodes completely.
+    pub fn set_unreachable(&self, node: u32, unreachable: &[u32]) -> Result<(), GlomerError> {
+        let set = self
+            .unreachable
+            .get(node as usize)
+            .ok_or_else(|| GlomerError::Unsupported(format!("No node {}", node_id(node))))?;
+        *set.write().unwrap() = unreachable.iter().copied().map(node_id).collect();
+        Ok(())
+    }
+
+    // Send a request from a client to the given node and wait for the reply.
+    // Body is the message body without msg_id, e.g. {"type": "read"}.
+    pub async fn rpc(&self, node: u32, mut body: Value) -> Result<Value, GlomerError> {
+        let input = self
+            .inputs
+            .get(node as usize)
+            .ok_or_else(|| GlomerError::Unsupported(format!("No node {}", node_id(node))))?;
+        let msg_id = self.next_msg_id.fetch_add(1, Ordering::SeqCst);
+        body["msg_id"] = msg_id.into();
+        let msg = json!({"src": CLIENT_ID, "dest": node_id(node), "body": body});
+
+        let (tx, rx) = oneshot::channel();
+        self.replies.lock().unwrap().insert(msg_id, tx);
+        let shut_down = || GlomerError::Abort("Cluster shut down.".into());
+        input.send(msg.to_string()).map_err(|_| shut_down())?;
+        match timeout(CLIENT_TIMEOUT, rx).await {
+            Ok(reply) => reply.map_err(|_| shut_down()),
+            Err(_) => {
+                self.replies.lock().unwrap().remove(&msg_id);
+                Err(GlomerError::Timeout)
+            }
+        }
+    }
+
+    // Repeatedly send request to every node until extract returns the same
+    // value for all of their replies, e.g. the messages a broadcast node has
+    // seen. Panics if the nodes haven't converged before the deadline.
+    pub async fn assert_converged<T, F>(&self, request: Value, extract: F, deadline: Duration) -> T
+    where
+        T: PartialEq + Debug,
+        F: Fn(&Value) -> T,
+    {
+        let deadline = Instant::now() + deadline;
+        loop {
+            let mut values = Vec::new();
+            for node in 0..self.inputs.len() as u32 {
+                match self.rpc(node, request.clone()).await {
+                    Ok(reply) => values.push(extract(&reply)),
+                    Err(err) => panic!("Request to {} failed: {err}", node_id(node)),
+                }
+            }
+            if values.windows(2).all(|pair| pair[0] == pair[1]) {
+                return values.swap_remove(0);
+            }
+            assert!(
+                Instant::now() < deadline,
+                "Nodes did not converge: {values:#?}"
+            );
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    // Close every node's input and wait for them to shut down
+    pub async fn shutdown(mut self) -> Result<(), GlomerError> {
+        self.shutdown.cancel();
+        let mut res = Ok(());
+        while let Some(node_res) = self.nodes.join_next().await {
+            match node_res {
+                Ok(node_res) => res = res.and(node_res),
+                Err(err) => panic::resume_unwind(err.into_panic()),
+            }
+        }
+        res
+    }
+}
+
+const CLIENT_ID: &str = "c1";
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+const CHANNEL_CAPACITY: usize = 64 * 1024;
+// How long TestHarness waits for more output before sending the next line
+const SETTLE_INTERVAL: Duration = Duration::from_millis(20);
+
+type PendingReplies = Mutex<BTreeMap<u64, oneshot::Sender<Value>>>;
+
+// Routes the output of one node
+struct Network {
+    simulator: Simulator,
+    inputs: Vec<mpsc::UnboundedSender<String>>,
+    replies: Arc<PendingReplies>,
+}
+
+impl Network {
+    async fn route(self, output: DuplexStream) {
+        let mut lines = BufReader::new(output).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let mut msg: Value = match serde_json::from_str(&line) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    eprintln!("ERROR: Simulated node wrote invalid message {line}: {err}");
+                    continue;
+                }
+            };
+            let dest = msg["dest"].as_str().unwrap_or_default();
+            let input = parse_node_id(dest)
+                .ok()
+                .and_then(|id| self.inputs.get(id as usize));
+            if let Some(input) = input {
+                self.deliver(input.clone(), line);
+            } else if dest != CLIENT_ID {
+                // e.g. init_ok replies, or messages to services we don't simulate
+                continue;
+            } else if let Some(in_reply_to) = msg["body"]["in_reply_to"].as_u64() {
+                if let Some(tx) = self.replies.lock().unwrap().remove(&in_reply_to) {
+                    let _ = tx.send(msg["body"].take());
+                }
+            }
+        }
+    }
+
+    fn deliver(&self, input: mpsc::UnboundedSender<String>, line: String) {
+        let mut rng = rand::thread_rng();
+        if rng.gen_bool(self.simulator.drop_rate) {
+            return;
+        }
+        if self.simulator.max_delay.is_zero() {
+            let _ = input.send(line);
+            return;
+        }
+        let delay = rng.gen_range(Duration::ZERO..=self.simulator.max_delay);
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let _ = input.send(line);
+        });
+    }
+}
+
+pub(super) fn init_line(id: &str, node_ids: &[String]) -> String {
+    json!({
+        "src": "c0",
+        "dest": id,
+        "body": {
+            "type": "init",
+            "msg_id": 0,
+            "node_id": id,
+            "node_ids": node_ids,
+        },
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tokio::time::{timeout, Duration};
+
+    use super::Simulator;
+    use crate::{error::MaelstromError, Handler, MaelstromMessage, Node};
+
+    // Replies to every request with its own body, typed echo_ok
+    struct Echo(Node);
+
+    impl Handler<Value> for Echo {
+        async fn handle(&self, msg: &MaelstromMessage<Value>) -> Result<(), MaelstromError> {
+            let mut body = msg.body.payload.clone();
+            body["type"] = "echo_ok".into();
+            self.0.reply(msg, body)?;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn cluster_shuts_down() {
+        let cluster = Simulator::new(3).start(Echo);
+        for node in 0..3 {
+            let reply = cluster
+                .rpc(node, json!({"type": "echo", "echo": node}))
+                .await
+                .unwrap();
+            assert_eq!(reply["type"], "echo_ok");
+            assert_eq!(reply["echo"], node);
+        }
+        timeout(Duration::from_secs(5), cluster.shutdown())
+            .await
+            .expect("Cluster didn't shut down")
+            .unwrap();
+    }
+}
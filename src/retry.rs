@@ -0,0 +1,54 @@
+use rand::Rng as _;
+use tokio::time::Duration;
+
+use crate::error::{ErrorCode, GlomerError, MaelstromError};
+
+// Controls how Node::send_rpc_retry retries a failed RPC
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // Total number of attempts, including the first one
+    pub max_attempts: u32,
+    // Delay before the first retry, doubled on every subsequent retry
+    pub base_delay: Duration,
+    // Upper bound on the delay between two attempts
+    pub max_delay: Duration,
+    // Timeout for each individual attempt
+    pub timeout: Option<Duration>,
+    // Error codes worth retrying, a GlomerError::Timeout counts as Timeout
+    pub retryable_codes: Vec<ErrorCode>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(1),
+            timeout: Some(Duration::from_secs(1)),
+            retryable_codes: vec![ErrorCode::Timeout, ErrorCode::TemporarilyUnavailable],
+        }
+    }
+}
+
+impl RetryPolicy {
+    #[must_use]
+    pub fn is_retryable(&self, err: &GlomerError) -> bool {
+        let code = match err {
+            GlomerError::Timeout => ErrorCode::Timeout,
+            GlomerError::Maelstrom(MaelstromError { code, .. }) => *code,
+            _ => return false,
+        };
+        self.retryable_codes.contains(&code)
+    }
+
+    // Exponential backoff with full jitter, so that nodes which failed at the
+    // same time don't all retry at the same time as well
+    #[must_use]
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        rand::thread_rng().gen_range(Duration::ZERO..=delay)
+    }
+}
@@ -0,0 +1,152 @@
+use std::{cmp::Ordering, collections::BTreeMap};
+
+use serde::{Deserialize, Serialize};
+
+// Vector clock keyed by node id (e.g. "n1"), serialized as a JSON object. Nodes
+// without an entry are at zero, comparisons included, so a clock received with
+// explicit zero entries equals one without them.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(transparent)]
+pub struct VectorClock(BTreeMap<String, u64>);
+
+impl VectorClock {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn get(&self, node: &str) -> u64 {
+        self.0.get(node).copied().unwrap_or_default()
+    }
+
+    // Record an event on node, returning its new counter
+    pub fn increment(&mut self, node: &str) -> u64 {
+        let counter = self.0.entry(node.to_string()).or_default();
+        *counter += 1;
+        *counter
+    }
+
+    // Entry-wise maximum, i.e. the earliest clock that has seen both
+    pub fn merge(&mut self, other: &Self) {
+        for (node, &counter) in &other.0 {
+            let entry = self.0.entry(node.clone()).or_default();
+            *entry = (*entry).max(counter);
+        }
+    }
+
+    // Every entry is at most the other's, and at least one is smaller
+    #[must_use]
+    pub fn happens_before(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Less)
+    }
+
+    // Neither clock happened before the other, e.g. conflicting writes
+    #[must_use]
+    pub fn concurrent(&self, other: &Self) -> bool {
+        self.partial_cmp(other).is_none()
+    }
+}
+
+// Consistent with partial_cmp, unlike comparing the maps
+impl PartialEq for VectorClock {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl Eq for VectorClock {}
+
+impl PartialOrd for VectorClock {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let mut ordering = Ordering::Equal;
+        for node in self.0.keys().chain(other.0.keys()) {
+            match (ordering, self.get(node).cmp(&other.get(node))) {
+                (_, Ordering::Equal) => {}
+                (Ordering::Equal, entry) => ordering = entry,
+                (current, entry) if current != entry => return None,
+                _ => {}
+            }
+        }
+        Some(ordering)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use super::VectorClock;
+
+    fn clock(entries: &[(&str, u64)]) -> VectorClock {
+        let mut clock = VectorClock::new();
+        for &(node, counter) in entries {
+            for _ in 0..counter {
+                clock.increment(node);
+            }
+        }
+        clock
+    }
+
+    #[test]
+    fn equal() {
+        let a = clock(&[("n1", 2), ("n2", 1)]);
+        let b = clock(&[("n2", 1), ("n1", 2)]);
+        assert_eq!(a, b);
+        assert_eq!(a.partial_cmp(&b), Some(Ordering::Equal));
+        assert!(!a.happens_before(&b));
+        assert!(!a.concurrent(&b));
+        assert_eq!(VectorClock::new(), VectorClock::default());
+    }
+
+    #[test]
+    fn missing_entries_equal_zero() {
+        let explicit: VectorClock = serde_json::from_str(r#"{"n1":1,"n2":0}"#).unwrap();
+        let implicit = clock(&[("n1", 1)]);
+        assert_eq!(explicit, implicit);
+        assert_eq!(explicit.partial_cmp(&implicit), Some(Ordering::Equal));
+        assert_ne!(explicit, clock(&[("n1", 1), ("n2", 1)]));
+    }
+
+    #[test]
+    fn dominates() {
+        let before = clock(&[("n1", 1)]);
+        let after = clock(&[("n1", 2), ("n2", 1)]);
+        assert!(before.happens_before(&after));
+        assert!(!after.happens_before(&before));
+        assert_eq!(after.partial_cmp(&before), Some(Ordering::Greater));
+        assert!(!before.concurrent(&after));
+        // A node missing from a clock is at zero
+        assert!(VectorClock::new().happens_before(&before));
+    }
+
+    #[test]
+    fn concurrent() {
+        let a = clock(&[("n1", 2), ("n2", 1)]);
+        let b = clock(&[("n1", 1), ("n2", 2)]);
+        assert!(a.concurrent(&b));
+        assert!(b.concurrent(&a));
+        assert!(!a.happens_before(&b));
+        assert!(!b.happens_before(&a));
+        assert_eq!(a.partial_cmp(&b), None);
+    }
+
+    #[test]
+    fn merge_dominates_both() {
+        let a = clock(&[("n1", 2), ("n2", 1)]);
+        let b = clock(&[("n1", 1), ("n3", 3)]);
+        let mut merged = a.clone();
+        merged.merge(&b);
+        assert_eq!(merged, clock(&[("n1", 2), ("n2", 1), ("n3", 3)]));
+        assert!(a.happens_before(&merged));
+        assert!(b.happens_before(&merged));
+    }
+
+    #[test]
+    fn serializes_as_object() {
+        let a = clock(&[("n1", 2), ("n2", 1)]);
+        let json = serde_json::to_string(&a).unwrap();
+        assert_eq!(json, r#"{"n1":2,"n2":1}"#);
+        assert_eq!(serde_json::from_str::<VectorClock>(&json).unwrap(), a);
+    }
+}
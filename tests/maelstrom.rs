@@ -0,0 +1,84 @@
+// Runs the workload binaries under the real Maelstrom harness. Skipped unless
+// MAELSTROM_JAR points at maelstrom.jar, since Maelstrom needs java and takes
+// a while. Same invocations as the justfile, with shorter time limits.
+
+use std::{env, process::Command};
+
+fn maelstrom(workload: &str, bin: &str, args: &[&str]) {
+    let Some(jar) = env::var_os("MAELSTROM_JAR") else {
+        eprintln!("MAELSTROM_JAR is not set, skipping {workload}");
+        return;
+    };
+    // Maelstrom writes its results to store/ in the working directory
+    let output = Command::new("java")
+        .arg("-jar")
+        .arg(jar)
+        .args(["test", "-w", workload, "--bin", bin])
+        .args(args)
+        .current_dir(env!("CARGO_TARGET_TMPDIR"))
+        .output()
+        .expect("Failed to run java");
+    assert!(
+        output.status.success(),
+        "Maelstrom {workload} failed with {}\nstdout:\n{}\nstderr:\n{}",
+        output.status,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn echo() {
+    maelstrom(
+        "echo",
+        env!("CARGO_BIN_EXE_echo"),
+        &["--node-count", "1", "--rate", "100", "--time-limit", "1"],
+    );
+}
+
+#[test]
+fn unique_ids() {
+    maelstrom(
+        "unique-ids",
+        env!("CARGO_BIN_EXE_unique_ids"),
+        &[
+            "--node-count",
+            "3",
+            "--rate",
+            "1000",
+            "--time-limit",
+            "5",
+            "--availability",
+            "total",
+            "--nemesis",
+            "partition",
+        ],
+    );
+}
+
+#[test]
+fn broadcast() {
+    maelstrom(
+        "broadcast",
+        env!("CARGO_BIN_EXE_broadcast"),
+        &["--node-count", "5", "--rate", "10", "--time-limit", "5"],
+    );
+}
+
+#[test]
+fn broadcast_partition() {
+    maelstrom(
+        "broadcast",
+        env!("CARGO_BIN_EXE_broadcast"),
+        &[
+            "--node-count",
+            "5",
+            "--rate",
+            "10",
+            "--time-limit",
+            "10",
+            "--nemesis",
+            "partition",
+        ],
+    );
+}